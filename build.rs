@@ -4,7 +4,9 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
-use core_isa_parser::{get_config, Chip, Value};
+use core_isa_parser::{
+    get_config, get_save_area_registers, get_tie_config, Chip, SaveAreaRegister, Value,
+};
 use minijinja::{context, Environment};
 
 fn main() {
@@ -40,6 +42,16 @@ fn handle_esp8266() {
         .unwrap()
         .write_all(exception_source)
         .unwrap();
+
+    generate_exception_frame(
+        out,
+        ESP8266_FRAME,
+        ESP8266_FRAME_SIZE,
+        ESP8266_SPILL_SIZE,
+        None,
+        false,
+        |_| true,
+    );
 }
 
 fn handle_esp32() {
@@ -82,11 +94,326 @@ fn handle_esp32() {
         _ => panic!("Either the esp32, esp32s2, esp32s3 or esp8266 feature must be enabled"),
     };
     let isa_config = get_config(chip).expect("Unable to parse ISA config");
+    let tie_config = get_tie_config(chip).expect("Unable to parse TIE config");
+    let cp1_registers =
+        get_save_area_registers(chip, 1).expect("Unable to parse the coprocessor 1 save area");
 
     inject_cfgs(&isa_config, &features_to_disable);
+    generate_exception_frame(
+        &out,
+        ESP32_FRAME,
+        ESP32_FRAME_SIZE,
+        ESP32_SPILL_SIZE,
+        Some(ESP32_FAST_FRAME_SIZE),
+        cfg!(feature = "compact-frames"),
+        |option| have(&isa_config, &features_to_disable, option),
+    );
     inject_cpu_cfgs(&isa_config);
+    inject_coprocessor_cfgs(&tie_config);
     generate_exception_x(&out, &isa_config);
     generate_interrupt_level_masks(&out, &isa_config);
+    generate_coprocessor_layout(&out, &tie_config, &cp1_registers);
+    generate_timer_interrupts(&out, &isa_config);
+    generate_profiling(&out, &isa_config);
+}
+
+/// Layout of the exception frame, shared by `Context` and the `XT_STK_*` offsets of the assembly.
+///
+/// In order, each slot is a register, the `XCHAL_HAVE_*` option it belongs to and its
+/// documentation. Registers of options the chip doesn't have are left out.
+type FrameLayout = &'static [(&'static str, Option<&'static str>, &'static str)];
+
+const ESP32_FRAME: FrameLayout = &[
+    ("PC", None, ""),
+    ("PS", None, ""),
+    ("A0", None, ""),
+    ("A1", None, ""),
+    ("A2", None, ""),
+    ("A3", None, ""),
+    ("A4", None, ""),
+    ("A5", None, ""),
+    ("A6", None, ""),
+    ("A7", None, ""),
+    ("A8", None, ""),
+    ("A9", None, ""),
+    ("A10", None, ""),
+    ("A11", None, ""),
+    ("A12", None, ""),
+    ("A13", None, ""),
+    ("A14", None, ""),
+    ("A15", None, ""),
+    ("SAR", None, ""),
+    ("EXCCAUSE", None, ""),
+    ("EXCVADDR", None, ""),
+    // the fast interrupt frame ends with the loop registers
+    ("LBEG", Some("XCHAL_HAVE_LOOPS"), ""),
+    ("LEND", Some("XCHAL_HAVE_LOOPS"), ""),
+    ("LCOUNT", Some("XCHAL_HAVE_LOOPS"), ""),
+    ("THREADPTR", Some("XCHAL_HAVE_THREADPTR"), ""),
+    ("SCOMPARE1", Some("XCHAL_HAVE_S32C1I"), ""),
+    ("BR", Some("XCHAL_HAVE_BOOLEANS"), ""),
+    ("ACCLO", Some("XCHAL_HAVE_MAC16"), ""),
+    ("ACCHI", Some("XCHAL_HAVE_MAC16"), ""),
+    ("M0", Some("XCHAL_HAVE_MAC16"), ""),
+    ("M1", Some("XCHAL_HAVE_MAC16"), ""),
+    ("M2", Some("XCHAL_HAVE_MAC16"), ""),
+    ("M3", Some("XCHAL_HAVE_MAC16"), ""),
+    ("F64R_LO", Some("XCHAL_HAVE_DFP_ACCEL"), ""),
+    ("F64R_HI", Some("XCHAL_HAVE_DFP_ACCEL"), ""),
+    ("F64S", Some("XCHAL_HAVE_DFP_ACCEL"), ""),
+    ("FCR", Some("XCHAL_HAVE_FP"), ""),
+    ("FSR", Some("XCHAL_HAVE_FP"), ""),
+    ("F0", Some("XCHAL_HAVE_FP"), ""),
+    ("F1", Some("XCHAL_HAVE_FP"), ""),
+    ("F2", Some("XCHAL_HAVE_FP"), ""),
+    ("F3", Some("XCHAL_HAVE_FP"), ""),
+    ("F4", Some("XCHAL_HAVE_FP"), ""),
+    ("F5", Some("XCHAL_HAVE_FP"), ""),
+    ("F6", Some("XCHAL_HAVE_FP"), ""),
+    ("F7", Some("XCHAL_HAVE_FP"), ""),
+    ("F8", Some("XCHAL_HAVE_FP"), ""),
+    ("F9", Some("XCHAL_HAVE_FP"), ""),
+    ("F10", Some("XCHAL_HAVE_FP"), ""),
+    ("F11", Some("XCHAL_HAVE_FP"), ""),
+    ("F12", Some("XCHAL_HAVE_FP"), ""),
+    ("F13", Some("XCHAL_HAVE_FP"), ""),
+    ("F14", Some("XCHAL_HAVE_FP"), ""),
+    ("F15", Some("XCHAL_HAVE_FP"), ""),
+    (
+        "CPFRAME",
+        None,
+        "Previous frame of this core when coprocessors are saved lazily (`lazy-fpu`, `pie`), scratch\n\
+         otherwise",
+    ),
+    (
+        "CPENABLE",
+        None,
+        "Interruptee's CPENABLE (bits 0-7) and the coprocessors saved to this frame (bits 8-15),\n\
+         only used when coprocessors are saved lazily (`lazy-fpu`, `pie`)",
+    ),
+];
+
+// A multiple of 256 allows the use of the addmi instruction, the spill region is enough for the
+// registers spilled to the stack (max 8 registers). With `compact-frames` the frame is only as
+// large as needed, rounded up to 16 bytes.
+const ESP32_FRAME_SIZE: usize = 256;
+const ESP32_SPILL_SIZE: usize = 0x20;
+// The frame of `#[interrupt(level, fast)]` handlers only holds the registers up to the loop
+// registers and the spill region. 128 still fits the addi instruction.
+const ESP32_FAST_FRAME_SIZE: usize = 128;
+
+const ESP8266_FRAME: FrameLayout = &[
+    ("PC", None, ""),
+    ("PS", None, ""),
+    ("A0", None, ""),
+    ("A1", None, ""),
+    ("A2", None, ""),
+    ("A3", None, ""),
+    ("A4", None, ""),
+    ("A5", None, ""),
+    ("A6", None, ""),
+    ("A7", None, ""),
+    ("A8", None, ""),
+    ("A9", None, ""),
+    ("A10", None, ""),
+    ("A11", None, ""),
+    ("A12", None, ""),
+    ("A13", None, ""),
+    ("A14", None, ""),
+    ("A15", None, ""),
+    ("SAR", None, ""),
+    ("EXCCAUSE", None, ""),
+    ("EXCVADDR", None, ""),
+];
+
+// The base save region at the end of the frame is 16 bytes
+const ESP8266_FRAME_SIZE: usize = 256;
+const ESP8266_SPILL_SIZE: usize = 16;
+
+fn generate_exception_frame(
+    out: &PathBuf,
+    layout: FrameLayout,
+    frame_size: usize,
+    spill_size: usize,
+    fast_frame_size: Option<usize>,
+    compact: bool,
+    have: impl Fn(&str) -> bool,
+) {
+    let mut offset = 0;
+    let mut fast_frame_end = 0;
+    let mut fields = Vec::new();
+    for (name, option, doc) in layout {
+        if option.map_or(true, |option| have(option)) {
+            fields.push(context! {
+                name => name,
+                offset => offset,
+                doc => doc.lines().filter(|line| !line.is_empty()).collect::<Vec<_>>(),
+            });
+            offset += 4;
+        }
+        if *name == "LCOUNT" {
+            fast_frame_end = offset;
+        }
+    }
+    assert!(
+        offset + spill_size <= frame_size,
+        "The exception frame is too small for the registers of this chip"
+    );
+    let frame_size = if compact {
+        (offset + spill_size + 15) & !15
+    } else {
+        frame_size
+    };
+
+    let mut env = Environment::new();
+    env.add_template("context.rs", include_str!("context.rs.jinja"))
+        .unwrap();
+    env.add_template(
+        "exception_frame.rs",
+        include_str!("exception_frame.rs.jinja"),
+    )
+    .unwrap();
+    for name in ["context.rs", "exception_frame.rs"] {
+        let source = env
+            .get_template(name)
+            .unwrap()
+            .render(context! {
+                FIELDS => fields,
+                FRAME_SIZE => frame_size,
+                SPILL_SIZE => spill_size,
+                FAST_FRAME_SIZE => fast_frame_size,
+                FAST_FRAME_END => fast_frame_end,
+            })
+            .unwrap();
+        File::create(out.join(name))
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+    }
+}
+
+fn generate_profiling(out: &PathBuf, isa_config: &HashMap<String, Value>) {
+    let integer = |key: &str| {
+        isa_config
+            .get(key)
+            .map(|v| v.as_integer())
+            .flatten()
+            .copied()
+    };
+
+    // without a profiling interrupt the values are never used
+    let interrupt = integer("XCHAL_PROFILING_INTERRUPT").unwrap_or(0);
+    let level = integer(&format!("XCHAL_INT{}_LEVEL", interrupt)).unwrap_or(0);
+    let level_mask = integer(&format!("XCHAL_INTLEVEL{}_MASK", level)).unwrap_or(0);
+
+    let mut env = Environment::new();
+    let profiling_source_template = &include_str!("profiling.rs.jinja")[..];
+    env.add_template("profiling.rs", profiling_source_template)
+        .unwrap();
+    let template = env.get_template("profiling.rs").unwrap();
+    let profiling_source = template
+        .render(context! {
+            XCHAL_PROFILING_INTERRUPT => interrupt,
+            XCHAL_PROFILING_LEVEL => level,
+            XCHAL_PROFILING_LEVEL_MASK => level_mask,
+        })
+        .unwrap();
+    File::create(out.join("profiling.rs"))
+        .unwrap()
+        .write_all(profiling_source.as_bytes())
+        .unwrap();
+}
+
+fn generate_timer_interrupts(out: &PathBuf, isa_config: &HashMap<String, Value>) {
+    // unconfigured timers are cfg'd away, their interrupt number is never used
+    let interrupt = |key: &str| {
+        isa_config
+            .get(key)
+            .map(|v| v.as_integer())
+            .flatten()
+            .copied()
+            .unwrap_or(0)
+    };
+
+    let mut env = Environment::new();
+    let timer_source_template = &include_str!("timer_interrupts.rs.jinja")[..];
+    env.add_template("timer_interrupts.rs", timer_source_template)
+        .unwrap();
+    let template = env.get_template("timer_interrupts.rs").unwrap();
+    let timer_source = template
+        .render(context! {
+            XCHAL_TIMER0_INTERRUPT => interrupt("XCHAL_TIMER0_INTERRUPT"),
+            XCHAL_TIMER1_INTERRUPT => interrupt("XCHAL_TIMER1_INTERRUPT"),
+            XCHAL_TIMER2_INTERRUPT => interrupt("XCHAL_TIMER2_INTERRUPT"),
+            XCHAL_TIMER3_INTERRUPT => interrupt("XCHAL_TIMER3_INTERRUPT"),
+        })
+        .unwrap();
+    File::create(out.join("timer_interrupts.rs"))
+        .unwrap()
+        .write_all(timer_source.as_bytes())
+        .unwrap();
+}
+
+fn generate_coprocessor_layout(
+    out: &PathBuf,
+    tie_config: &HashMap<String, Value>,
+    cp1_registers: &[SaveAreaRegister],
+) {
+    let size = tie_config
+        .get("XCHAL_CP1_SA_SIZE")
+        .map(|v| v.as_integer())
+        .flatten()
+        .copied()
+        .unwrap_or(0);
+    let align = tie_config
+        .get("XCHAL_CP1_SA_ALIGN")
+        .map(|v| v.as_integer())
+        .flatten()
+        .copied()
+        .unwrap_or(1);
+
+    // the registers are laid out in order, each one aligned to its own alignment
+    let mut offset = 0;
+    let mut registers = Vec::new();
+    for register in cp1_registers {
+        offset = (offset + register.align - 1) & !(register.align - 1);
+        registers.push(context! {
+            name => register.name,
+            offset => offset,
+        });
+        offset += register.size;
+    }
+    assert!(
+        offset as i64 <= size,
+        "The coprocessor 1 registers don't fit into XCHAL_CP1_SA_SIZE"
+    );
+
+    let mut env = Environment::new();
+    env.add_template(
+        "coprocessor_layout.rs",
+        include_str!("coprocessor_layout.rs.jinja"),
+    )
+    .unwrap();
+    env.add_template(
+        "coprocessor_save_area.rs",
+        include_str!("coprocessor_save_area.rs.jinja"),
+    )
+    .unwrap();
+    for name in ["coprocessor_layout.rs", "coprocessor_save_area.rs"] {
+        let source = env
+            .get_template(name)
+            .unwrap()
+            .render(context! {
+                XCHAL_CP1_SA_SIZE => size,
+                XCHAL_CP1_SA_ALIGN => align,
+                REGISTERS => registers,
+            })
+            .unwrap();
+        File::create(out.join(name))
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+    }
 }
 
 fn generate_interrupt_level_masks(out: &PathBuf, isa_config: &HashMap<String, Value>) {
@@ -95,6 +422,14 @@ fn generate_interrupt_level_masks(out: &PathBuf, isa_config: &HashMap<String, Va
     env.add_template("interrupt_level_masks.rs", exception_source_template)
         .unwrap();
     let template = env.get_template("interrupt_level_masks.rs").unwrap();
+    // only edge triggered and software interrupts are cleared through INTCLEAR
+    let intclear_mask = [
+        "XCHAL_INTTYPE_MASK_EXTERN_EDGE",
+        "XCHAL_INTTYPE_MASK_SOFTWARE",
+    ]
+    .iter()
+    .filter_map(|key| isa_config.get(*key).map(|v| v.as_integer()).flatten())
+    .fold(0, |mask, value| mask | value);
     let exception_source = template
         .render(context! {
             XCHAL_INTLEVEL1_MASK => isa_config.get("XCHAL_INTLEVEL1_MASK").unwrap().as_integer(),
@@ -104,6 +439,7 @@ fn generate_interrupt_level_masks(out: &PathBuf, isa_config: &HashMap<String, Va
             XCHAL_INTLEVEL5_MASK => isa_config.get("XCHAL_INTLEVEL5_MASK").unwrap().as_integer(),
             XCHAL_INTLEVEL6_MASK => isa_config.get("XCHAL_INTLEVEL6_MASK").unwrap().as_integer(),
             XCHAL_INTLEVEL7_MASK => isa_config.get("XCHAL_INTLEVEL7_MASK").unwrap().as_integer(),
+            INTCLEAR_MASK => intclear_mask,
         })
         .unwrap();
     File::create(out.join("interrupt_level_masks.rs"))
@@ -135,6 +471,7 @@ fn generate_exception_x(out: &PathBuf, isa_config: &HashMap<String, Value>) {
             XCHAL_KERNEL_VECOFS => isa_config.get("XCHAL_KERNEL_VECOFS").unwrap().as_integer(),
             XCHAL_USER_VECOFS => isa_config.get("XCHAL_USER_VECOFS").unwrap().as_integer(),
             XCHAL_DOUBLEEXC_VECOFS => isa_config.get("XCHAL_DOUBLEEXC_VECOFS").unwrap().as_integer(),
+            PROFILER => cfg!(feature = "profiler"),
         }
     ).unwrap();
     File::create(out.join("exception.x"))
@@ -144,15 +481,27 @@ fn generate_exception_x(out: &PathBuf, isa_config: &HashMap<String, Value>) {
 }
 
 fn inject_cfgs(isa_config: &HashMap<String, Value>, disabled_features: &HashSet<String>) {
-    for (key, value) in isa_config {
-        if key.starts_with("XCHAL_HAVE") && *value.as_integer().unwrap_or(&0) != 0 {
-            if !disabled_features.contains(key) {
-                println!("cargo:rustc-cfg={}", key);
-            }
+    for key in isa_config.keys() {
+        if key.starts_with("XCHAL_HAVE") && have(isa_config, disabled_features, key) {
+            println!("cargo:rustc-cfg={}", key);
         }
     }
 }
 
+/// Whether the option `key` is configured and not disabled with `-Ctarget-feature`
+fn have(
+    isa_config: &HashMap<String, Value>,
+    disabled_features: &HashSet<String>,
+    key: &str,
+) -> bool {
+    isa_config
+        .get(key)
+        .map(|v| v.as_integer())
+        .flatten()
+        .map_or(false, |value| *value != 0)
+        && !disabled_features.contains(key)
+}
+
 fn inject_cpu_cfgs(isa_config: &HashMap<String, Value>) {
     for (key, value) in isa_config {
         if key.starts_with("XCHAL_TIMER")
@@ -178,6 +527,19 @@ fn inject_cpu_cfgs(isa_config: &HashMap<String, Value>) {
     }
 }
 
+fn inject_coprocessor_cfgs(tie_config: &HashMap<String, Value>) {
+    // Coprocessor 1 is the PIE vector unit on the ESP32-S3
+    if let Some(size) = tie_config
+        .get("XCHAL_CP1_SA_SIZE")
+        .map(|v| v.as_integer())
+        .flatten()
+    {
+        if *size != 0 {
+            println!("cargo:rustc-cfg=XCHAL_HAVE_CP1");
+        }
+    }
+}
+
 fn rustc_feature_to_xchal_have(s: &str) -> Option<&str> {
     // List of rustc features taken from here:
     // https://github.com/esp-rs/rust/blob/84ecb3f010525cb1b2e7d4da306099c2eaa3e6cd/compiler/rustc_codegen_ssa/src/target_features.rs#L278
@@ -1,4 +1,4 @@
-//! Parse the core-isa.h headers from Espressif's xtensa-overlays repository.
+//! Parse the core-isa.h and tie.h headers from Espressif's xtensa-overlays repository.
 //!
 //! <https://github.com/espressif/xtensa-overlays>
 
@@ -27,10 +27,19 @@ pub enum Chip {
 
 impl Chip {
     fn core_isa_path(&self) -> Result<PathBuf> {
+        self.config_path("core-isa.h")
+    }
+
+    fn tie_path(&self) -> Result<PathBuf> {
+        self.config_path("tie.h")
+    }
+
+    fn config_path(&self, header: &str) -> Result<PathBuf> {
         let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("xtensa-overlays")
             .join(self.to_string())
-            .join("newlib/newlib/libc/sys/xtensa/include/xtensa/config/core-isa.h")
+            .join("newlib/newlib/libc/sys/xtensa/include/xtensa/config")
+            .join(header)
             .canonicalize()?;
 
         Ok(path)
@@ -69,6 +78,62 @@ pub enum Value {
 /// Returns a hashmap with the definition identifiers as keys and the
 /// corresponding parsed values as values.
 pub fn get_config(chip: Chip) -> Result<HashMap<String, Value>> {
+    parse_defines(chip, find_all_defines(chip.core_isa_path()?)?)
+}
+
+/// Parse the TIE configuration (coprocessor state save areas) for the given chip
+///
+/// Returns a hashmap with the definition identifiers as keys and the
+/// corresponding parsed values as values.
+pub fn get_tie_config(chip: Chip) -> Result<HashMap<String, Value>> {
+    parse_defines(chip, find_all_defines(chip.tie_path()?)?)
+}
+
+/// A register of a coprocessor state save area, as listed by the `XCHAL_CPn_SA_LIST` macros of
+/// the `tie.h` headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveAreaRegister {
+    /// The register name, e.g. `accx_0` or `q0`
+    pub name: String,
+    /// The alignment of the register in the save area in bytes
+    pub align: u32,
+    /// The size of the register in the save area in bytes
+    pub size: u32,
+}
+
+/// Parse the state save area of coprocessor `cp` for the given chip
+///
+/// Returns the registers in the order they are stored, empty if the chip doesn't have the
+/// coprocessor.
+pub fn get_save_area_registers(chip: Chip, cp: u32) -> Result<Vec<SaveAreaRegister>> {
+    parse_save_area_list(&find_macro(
+        chip.tie_path()?,
+        &format!("XCHAL_CP{}_SA_LIST", cp),
+    )?)
+}
+
+fn parse_save_area_list(list: &str) -> Result<Vec<SaveAreaRegister>> {
+    // XCHAL_SA_REG(s,ccused,abikind,kind,opt,name,galign,align,asize,dbnum,base,regnum,bitsz,gapsz,reset,x)
+    let re_reg = Regex::new(r"XCHAL_SA_REG\(([^)]*)\)")?;
+
+    let mut registers = Vec::new();
+    for captures in re_reg.captures_iter(list) {
+        let args = captures[1].split(',').map(str::trim).collect::<Vec<_>>();
+        if args.len() != 16 {
+            anyhow::bail!("Unexpected save area register: {}", &captures[0]);
+        }
+
+        registers.push(SaveAreaRegister {
+            name: args[5].to_string(),
+            align: args[7].parse()?,
+            size: args[8].parse()?,
+        });
+    }
+
+    Ok(registers)
+}
+
+fn parse_defines(chip: Chip, defines: Vec<String>) -> Result<HashMap<String, Value>> {
     let re_define = Regex::new(r"^#define[\s]+([a-zA-Z\d_]+)[\s]+([^\s]+)")?;
     let re_ident = Regex::new(r"^[a-zA-Z\d_]+$")?;
     let re_string = Regex::new(r#""([^"]+)""#)?;
@@ -76,7 +141,7 @@ pub fn get_config(chip: Chip) -> Result<HashMap<String, Value>> {
     // Iterate through each line containing a definition. Attempt to match the
     // various components and map identifiers to values.
     let mut map: HashMap<String, Value> = HashMap::new();
-    for define in find_all_defines(chip)? {
+    for define in defines {
         if !re_define.is_match(&define) {
             println!("Define not matched: {}", define);
             continue;
@@ -115,8 +180,27 @@ pub fn get_config(chip: Chip) -> Result<HashMap<String, Value>> {
     Ok(map)
 }
 
-fn find_all_defines(chip: Chip) -> Result<Vec<String>> {
-    let path = chip.core_isa_path()?;
+/// Find the definition of the function-like macro `name`, joining its continuation lines
+fn find_macro(path: PathBuf, name: &str) -> Result<String> {
+    let header = fs::read_to_string(path)?;
+    let start = format!("#define {}(", name);
+
+    let lines = header.lines().skip_while(|line| !line.starts_with(&start));
+    let mut definition = String::new();
+    for line in lines {
+        match line.strip_suffix('\\') {
+            Some(line) => definition.push_str(line),
+            None => {
+                definition.push_str(line);
+                break;
+            }
+        }
+    }
+
+    Ok(definition)
+}
+
+fn find_all_defines(path: PathBuf) -> Result<Vec<String>> {
     let lines = fs::read_to_string(path)?
         .lines()
         .filter_map(|line| {
@@ -2,16 +2,17 @@
 
 /* high level exception/interrupt routines, which can be override with Rust functions */
 PROVIDE(__exception = __default_exception);
-PROVIDE(__kernel_exception = __default_exception);
+PROVIDE(__user_exception = __default_user_exception);
+PROVIDE(__kernel_exception = __user_exception);
 PROVIDE(__double_exception = __default_double_exception);
-PROVIDE(__nmi_exception = __default_exception);
-PROVIDE(__debug_exception = __default_exception);
 PROVIDE(__alloc_exception = __default_exception);
 PROVIDE(__level_1_interrupt = __default_interrupt);
+PROVIDE(__level_2_interrupt = __default_interrupt); /* debug exception */
+PROVIDE(__level_3_interrupt = __default_interrupt); /* NMI */
 
 /* low level exception/interrupt, which must be overridden using naked functions */
 PROVIDE(__naked_user_exception = __default_naked_exception);
-PROVIDE(__naked_kernel_exception = __default_naked_kernel_exception);
+PROVIDE(__naked_kernel_exception = __default_naked_exception);
 PROVIDE(__naked_double_exception = __default_naked_double_exception);
 PROVIDE(__naked_nmi_exception = __default_naked_nmi_exception);
 PROVIDE(__naked_debug_exception = __default_naked_debug_exception);
@@ -22,7 +23,6 @@ EXTERN(__default_exception);
 EXTERN(__default_double_exception);
 EXTERN(__default_interrupt);
 
-EXTERN(__default_naked_exception);
 EXTERN(__default_naked_exception);
 EXTERN(__default_naked_double_exception);
 EXTERN(__default_naked_nmi_exception);
@@ -0,0 +1,714 @@
+//! GDB remote serial protocol engine
+//!
+//! The target independent part of the `gdbstub` feature of `xtensa-lx-rt`: [`Stub`] talks to GDB
+//! over a [`Transport`] and accesses the stopped program through a [`Target`]. Neither needs
+//! hardware, so the engine runs on the host as well, e.g. against a loopback transport.
+//!
+//! Supported packets are `?`, `g`, `G`, `p`, `P`, `m`, `M`, `Z0`/`z0` (software breakpoints),
+//! `Z1`/`z1` (hardware breakpoints), `c`, `s`, `D`, `k`, `H`, `qSupported` and `qAttached`. Other
+//! packets get the empty reply, which tells GDB they are not supported.
+
+#![no_std]
+
+/// Size of the packet buffer, the largest packet GDB may send
+pub const PACKET_SIZE: usize = 1024;
+
+/// The connection to GDB, e.g. a UART
+pub trait Transport {
+    /// Wait for and return the next byte from GDB
+    fn read(&mut self) -> u8;
+    /// Send a byte to GDB
+    fn write(&mut self, byte: u8);
+    /// Send buffered bytes, called at the end of every packet
+    fn flush(&mut self) {}
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn read(&mut self) -> u8 {
+        (**self).read()
+    }
+
+    fn write(&mut self, byte: u8) {
+        (**self).write(byte)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+}
+
+/// A breakpoint requested by GDB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Replace the instruction at the address with a `break` instruction of `length` bytes
+    Software { length: u32 },
+    /// Use an instruction breakpoint register
+    Hardware,
+}
+
+/// The stopped program
+pub trait Target {
+    /// Number of registers in the `g` packet
+    fn register_count(&self) -> usize;
+    /// Register `number` in GDB's numbering, `None` if it isn't available
+    fn read_register(&mut self, number: usize) -> Option<u32>;
+    /// Returns whether the register was written
+    fn write_register(&mut self, number: usize, value: u32) -> bool;
+    /// Returns whether the memory could be read
+    fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> bool;
+    /// Returns whether the memory could be written
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool;
+
+    /// Returns whether the breakpoint was set
+    fn add_breakpoint(&mut self, _kind: Breakpoint, _address: u32) -> bool {
+        false
+    }
+
+    /// Returns whether the breakpoint was removed
+    fn remove_breakpoint(&mut self, _kind: Breakpoint, _address: u32) -> bool {
+        false
+    }
+}
+
+/// How GDB lets the program go on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Continue, at the address if given
+    Continue(Option<u32>),
+    /// Execute a single instruction, at the address if given
+    Step(Option<u32>),
+    /// GDB detached, continue without debugger
+    Detach,
+    /// GDB killed the program
+    Kill,
+}
+
+/// The protocol engine
+pub struct Stub<T> {
+    transport: T,
+    buffer: [u8; PACKET_SIZE],
+    /// The `$` of the next packet was already received while waiting for an acknowledgement
+    started: bool,
+}
+
+impl<T: Transport> Stub<T> {
+    pub const fn new(transport: T) -> Self {
+        Stub {
+            transport,
+            buffer: [0; PACKET_SIZE],
+            started: false,
+        }
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Report a stop with `signal` and serve GDB until it resumes the program
+    pub fn run(&mut self, target: &mut impl Target, signal: u8) -> Resume {
+        let len = stop_reply(&mut self.buffer, signal);
+        self.send(len);
+
+        loop {
+            let len = self.receive();
+            let (reply, resume) = handle(&mut self.buffer, len, target, signal);
+            if let Some(len) = reply {
+                self.send(len);
+            }
+            if let Some(resume) = resume {
+                return resume;
+            }
+        }
+    }
+
+    /// Receive a packet into the buffer and acknowledge it, returns its length
+    fn receive(&mut self) -> usize {
+        loop {
+            if !self.started {
+                while self.transport.read() != b'$' {}
+            }
+            self.started = false;
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                match self.transport.read() {
+                    b'#' => break,
+                    // the previous packet was cut off
+                    b'$' => {
+                        len = 0;
+                        sum = 0;
+                        overflow = false;
+                    }
+                    byte => {
+                        sum = sum.wrapping_add(byte);
+                        match self.buffer.get_mut(len) {
+                            Some(slot) => *slot = byte,
+                            None => overflow = true,
+                        }
+                        len += 1;
+                    }
+                }
+            }
+            let high = hex_value(self.transport.read());
+            let low = hex_value(self.transport.read());
+
+            let valid = !overflow && high.zip(low).map(|(high, low)| high << 4 | low) == Some(sum);
+            self.transport.write(if valid { b'+' } else { b'-' });
+            self.transport.flush();
+            if valid {
+                return len;
+            }
+        }
+    }
+
+    /// Send the first `len` bytes of the buffer as packet, until GDB acknowledges it
+    fn send(&mut self, len: usize) {
+        loop {
+            let sum = self.buffer[..len]
+                .iter()
+                .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            self.transport.write(b'$');
+            for &byte in &self.buffer[..len] {
+                self.transport.write(byte);
+            }
+            self.transport.write(b'#');
+            self.transport.write(HEX[(sum >> 4) as usize]);
+            self.transport.write(HEX[(sum & 0xf) as usize]);
+            self.transport.flush();
+
+            loop {
+                match self.transport.read() {
+                    b'+' => return,
+                    b'-' => break,
+                    // GDB sent the next packet without acknowledging, e.g. when it connects
+                    b'$' => {
+                        self.started = true;
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Handle the packet in `buffer[..len]`, returns the length of the reply left in the buffer, if
+/// any, and whether to resume the program
+fn handle(
+    buffer: &mut [u8; PACKET_SIZE],
+    len: usize,
+    target: &mut impl Target,
+    signal: u8,
+) -> (Option<usize>, Option<Resume>) {
+    let packet = &buffer[..len];
+    let (&command, arguments) = match packet.split_first() {
+        Some(split) => split,
+        None => return (Some(0), None),
+    };
+
+    let reply = match command {
+        b'?' => stop_reply(buffer, signal),
+        b'g' => {
+            let mut reply = Reply::new(buffer);
+            for number in 0..target.register_count() {
+                reply.register(target.read_register(number));
+            }
+            reply.len
+        }
+        b'G' => {
+            let count = target.register_count().min(arguments.len() / 8);
+            for number in 0..count {
+                let hex = &buffer[1 + number * 8..1 + (number + 1) * 8];
+                if let Some(value) = parse_register(hex) {
+                    target.write_register(number, value);
+                }
+            }
+            ok(buffer)
+        }
+        b'p' => match parse_hex(arguments) {
+            Some(number) if (number as usize) < target.register_count() => {
+                let value = target.read_register(number as usize);
+                let mut reply = Reply::new(buffer);
+                reply.register(value);
+                reply.len
+            }
+            _ => error(buffer, 1),
+        },
+        b'P' => {
+            let parsed = split(arguments, b'=').and_then(|(number, value)| {
+                Some((parse_hex(number)? as usize, parse_register(value)?))
+            });
+            match parsed {
+                Some((number, value)) if target.write_register(number, value) => ok(buffer),
+                _ => error(buffer, 1),
+            }
+        }
+        b'm' => match parse_address_length(arguments) {
+            Some((address, length)) => {
+                let length = (length as usize).min(PACKET_SIZE / 2);
+                let mut chunk = [0u8; 64];
+                let mut reply = Reply::new(buffer);
+                let mut done = 0;
+                while done < length {
+                    let n = (length - done).min(chunk.len());
+                    if !target.read_memory(address.wrapping_add(done as u32), &mut chunk[..n]) {
+                        break;
+                    }
+                    for &byte in &chunk[..n] {
+                        reply.byte(byte);
+                    }
+                    done += n;
+                }
+                if done == 0 && length > 0 {
+                    error(buffer, 14)
+                } else {
+                    reply.len
+                }
+            }
+            None => error(buffer, 1),
+        },
+        b'M' => {
+            let parsed = split(arguments, b':').and_then(|(location, data)| {
+                let (address, length) = parse_address_length(location)?;
+                // nothing is written unless all of the data is valid
+                let valid = data.iter().all(|&digit| hex_value(digit).is_some());
+                (valid && data.len() == length as usize * 2).then(|| (address, len - data.len()))
+            });
+            match parsed {
+                Some((address, start)) => {
+                    let mut chunk = [0u8; 64];
+                    let mut written = true;
+                    for (i, hex) in buffer[start..len].chunks(chunk.len() * 2).enumerate() {
+                        let n = hex.len() / 2;
+                        for (byte, pair) in chunk.iter_mut().zip(hex.chunks(2)) {
+                            // the digits were validated above
+                            *byte = parse_hex(pair).unwrap_or(0) as u8;
+                        }
+                        let offset = (i * chunk.len()) as u32;
+                        written &= target.write_memory(address.wrapping_add(offset), &chunk[..n]);
+                    }
+                    if written {
+                        ok(buffer)
+                    } else {
+                        error(buffer, 14)
+                    }
+                }
+                None => error(buffer, 1),
+            }
+        }
+        b'Z' | b'z' => {
+            let mut fields = arguments.split(|&byte| byte == b',');
+            let kind = fields.next();
+            let address = fields.next().and_then(parse_hex);
+            let length = fields.next().and_then(parse_hex);
+            let kind = match (kind, length) {
+                (Some(b"0"), Some(length)) => Some(Breakpoint::Software { length }),
+                (Some(b"1"), _) => Some(Breakpoint::Hardware),
+                _ => None,
+            };
+            match (kind, address) {
+                (Some(kind), Some(address)) => {
+                    let done = if command == b'Z' {
+                        target.add_breakpoint(kind, address)
+                    } else {
+                        target.remove_breakpoint(kind, address)
+                    };
+                    if done {
+                        ok(buffer)
+                    } else {
+                        error(buffer, 14)
+                    }
+                }
+                // watchpoints are not supported
+                (None, _) => 0,
+                (_, None) => error(buffer, 1),
+            }
+        }
+        b'c' => return (None, Some(Resume::Continue(parse_hex(arguments)))),
+        b's' => return (None, Some(Resume::Step(parse_hex(arguments)))),
+        b'D' => return (Some(ok(buffer)), Some(Resume::Detach)),
+        b'k' => return (None, Some(Resume::Kill)),
+        b'H' => ok(buffer),
+        b'q' if packet.starts_with(b"qSupported") => {
+            let mut reply = Reply::new(buffer);
+            reply.str("PacketSize=");
+            reply.hex(PACKET_SIZE as u32);
+            reply.len
+        }
+        b'q' if packet.starts_with(b"qAttached") => {
+            let mut reply = Reply::new(buffer);
+            reply.str("1");
+            reply.len
+        }
+        _ => 0,
+    };
+    (Some(reply), None)
+}
+
+/// A reply written to the start of the packet buffer
+struct Reply<'a> {
+    buffer: &'a mut [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl<'a> Reply<'a> {
+    fn new(buffer: &'a mut [u8; PACKET_SIZE]) -> Self {
+        Reply { buffer, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if let Some(slot) = self.buffer.get_mut(self.len) {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+    }
+
+    /// Two hex digits
+    fn byte(&mut self, byte: u8) {
+        self.push(HEX[(byte >> 4) as usize]);
+        self.push(HEX[(byte & 0xf) as usize]);
+    }
+
+    /// A number in hex, without leading zeros
+    fn hex(&mut self, value: u32) {
+        let digits = ((32 - value.leading_zeros()).max(1) + 3) / 4;
+        for i in (0..digits).rev() {
+            self.push(HEX[((value >> (i * 4)) & 0xf) as usize]);
+        }
+    }
+
+    /// A register in target byte order, `x` for unavailable registers
+    fn register(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => {
+                for byte in value.to_le_bytes() {
+                    self.byte(byte);
+                }
+            }
+            None => self.str("xxxxxxxx"),
+        }
+    }
+}
+
+fn stop_reply(buffer: &mut [u8; PACKET_SIZE], signal: u8) -> usize {
+    let mut reply = Reply::new(buffer);
+    reply.push(b'S');
+    reply.byte(signal);
+    reply.len
+}
+
+fn ok(buffer: &mut [u8; PACKET_SIZE]) -> usize {
+    let mut reply = Reply::new(buffer);
+    reply.str("OK");
+    reply.len
+}
+
+fn error(buffer: &mut [u8; PACKET_SIZE], errno: u8) -> usize {
+    let mut reply = Reply::new(buffer);
+    reply.push(b'E');
+    reply.byte(errno);
+    reply.len
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A hex number, `None` if empty, too long or not hex
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | hex_value(digit)? as u32)
+    })
+}
+
+/// A register value in target byte order
+fn parse_register(hex: &[u8]) -> Option<u32> {
+    if hex.len() != 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 4];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = parse_hex(pair)? as u8;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// `address,length`
+fn parse_address_length(arguments: &[u8]) -> Option<(u32, u32)> {
+    let (address, length) = split(arguments, b',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..position], &bytes[position + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    const SIGTRAP: u8 = 5;
+
+    /// Plays GDB's side from a script and records everything the stub sends
+    #[derive(Default)]
+    struct Loopback {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn script(parts: &[&[u8]]) -> Self {
+            Loopback {
+                input: parts.concat().into_iter().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Transport for Loopback {
+        fn read(&mut self) -> u8 {
+            self.input
+                .pop_front()
+                .expect("the stub read past the script")
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    struct MockTarget {
+        registers: [u32; 4],
+        memory: [u8; 16],
+        breakpoints: Vec<(Breakpoint, u32)>,
+    }
+
+    const MEMORY: u32 = 0x3ffb_0000;
+
+    impl MockTarget {
+        fn new() -> Self {
+            MockTarget {
+                registers: [0x4008_1234, 0x0006_0030, 0, 0xdead_beef],
+                memory: core::array::from_fn(|i| i as u8),
+                breakpoints: Vec::new(),
+            }
+        }
+
+        fn range(&self, address: u32, len: usize) -> Option<core::ops::Range<usize>> {
+            let start = address.checked_sub(MEMORY)? as usize;
+            (start + len <= self.memory.len()).then(|| start..start + len)
+        }
+    }
+
+    impl Target for MockTarget {
+        fn register_count(&self) -> usize {
+            self.registers.len()
+        }
+
+        fn read_register(&mut self, number: usize) -> Option<u32> {
+            // the third register is not available
+            self.registers.get(number).copied().filter(|_| number != 2)
+        }
+
+        fn write_register(&mut self, number: usize, value: u32) -> bool {
+            match self.registers.get_mut(number) {
+                Some(register) => {
+                    *register = value;
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> bool {
+            match self.range(address, buffer.len()) {
+                Some(range) => {
+                    buffer.copy_from_slice(&self.memory[range]);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+            match self.range(address, data.len()) {
+                Some(range) => {
+                    self.memory[range].copy_from_slice(data);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn add_breakpoint(&mut self, kind: Breakpoint, address: u32) -> bool {
+            self.breakpoints.push((kind, address));
+            true
+        }
+
+        fn remove_breakpoint(&mut self, kind: Breakpoint, address: u32) -> bool {
+            let before = self.breakpoints.len();
+            self.breakpoints.retain(|&b| b != (kind, address));
+            self.breakpoints.len() != before
+        }
+    }
+
+    /// `$data#checksum`
+    fn packet(data: &str) -> Vec<u8> {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        std::format!("${}#{:02x}", data, sum).into_bytes()
+    }
+
+    /// Run the stub against a GDB script, the stop reply is acknowledged before the script
+    fn run(target: &mut MockTarget, script: &[&[u8]]) -> (Resume, String) {
+        let mut parts = std::vec![&b"+"[..]];
+        parts.extend_from_slice(script);
+        let mut stub = Stub::new(Loopback::script(&parts));
+        let resume = stub.run(target, SIGTRAP);
+        assert!(
+            stub.transport().input.is_empty(),
+            "the script wasn't used up"
+        );
+        (
+            resume,
+            String::from_utf8(stub.transport().output.clone()).unwrap(),
+        )
+    }
+
+    /// Send `command` and acknowledge the reply, then continue
+    ///
+    /// Returns the data of the reply after checking its checksum.
+    fn exchange(target: &mut MockTarget, command: &str) -> String {
+        let (resume, output) = run(target, &[&packet(command), b"+", &packet("c")]);
+        assert_eq!(resume, Resume::Continue(None));
+        // the stop reply and `+` for the command before, `+` for `c` after the reply
+        let reply = output
+            .strip_prefix("$S05#b8+")
+            .and_then(|rest| rest.strip_suffix('+'))
+            .unwrap_or_else(|| panic!("unexpected output {:?}", output));
+        let (data, _) = reply[1..].split_once('#').unwrap();
+        assert_eq!(reply.as_bytes(), packet(data));
+        String::from(data)
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(packet("S05"), b"$S05#b8");
+        assert_eq!(packet("OK"), b"$OK#9a");
+        assert_eq!(parse_hex(b"3ffb0000"), Some(0x3ffb_0000));
+        assert_eq!(parse_hex(b"123456789"), None);
+        assert_eq!(parse_register(b"34120840"), Some(0x4008_1234));
+    }
+
+    #[test]
+    fn nak_and_retransmit() {
+        let mut target = MockTarget::new();
+        // a corrupted packet is rejected and GDB sends it again
+        let (resume, output) = run(
+            &mut target,
+            &[b"$?#00", &packet("?"), b"-", b"+", &packet("c")],
+        );
+        assert_eq!(resume, Resume::Continue(None));
+        // the stop reply, `-` for the corrupted packet, `+` for the good one, then the reply to
+        // `?` twice because GDB rejected the first copy, and `+` for `c`
+        assert_eq!(output, "$S05#b8-+$S05#b8$S05#b8+");
+    }
+
+    #[test]
+    fn stop_reason() {
+        assert_eq!(exchange(&mut MockTarget::new(), "?"), "S05");
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut target = MockTarget::new();
+        assert_eq!(
+            exchange(&mut target, "g"),
+            "3412084030000600xxxxxxxxefbeadde"
+        );
+        assert_eq!(exchange(&mut target, "p0"), "34120840");
+        assert_eq!(exchange(&mut target, "p2"), "xxxxxxxx");
+        assert_eq!(exchange(&mut target, "p4"), "E01");
+    }
+
+    #[test]
+    fn write_registers() {
+        let mut target = MockTarget::new();
+        assert_eq!(
+            exchange(&mut target, "G0100000002000000030000000400000"),
+            "OK"
+        );
+        // the last register was cut off and is left alone
+        assert_eq!(target.registers, [1, 2, 3, 0xdead_beef]);
+        assert_eq!(exchange(&mut target, "P3=78563412"), "OK");
+        assert_eq!(target.registers[3], 0x1234_5678);
+        assert_eq!(exchange(&mut target, "P9=00000000"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut target = MockTarget::new();
+        assert_eq!(exchange(&mut target, "m3ffb0002,4"), "02030405");
+        assert_eq!(exchange(&mut target, "M3ffb0004,2:aa55"), "OK");
+        assert_eq!(&target.memory[3..7], &[3, 0xaa, 0x55, 6]);
+        assert_eq!(exchange(&mut target, "m40000000,4"), "E0e");
+        assert_eq!(exchange(&mut target, "M3ffb0000,2:aa"), "E01");
+        // malformed hex is rejected without writing any of the data
+        assert_eq!(exchange(&mut target, "M3ffb0000,4:01x20304"), "E01");
+        assert_eq!(exchange(&mut target, "M3ffb0000,2:01g2"), "E01");
+        assert_eq!(&target.memory[..4], &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut target = MockTarget::new();
+        assert_eq!(exchange(&mut target, "Z0,400d1000,3"), "OK");
+        assert_eq!(
+            target.breakpoints,
+            [(Breakpoint::Software { length: 3 }, 0x400d_1000)]
+        );
+        assert_eq!(exchange(&mut target, "z0,400d1000,3"), "OK");
+        assert!(target.breakpoints.is_empty());
+        assert_eq!(exchange(&mut target, "z0,400d1000,3"), "E0e");
+        // watchpoints are not supported
+        assert_eq!(exchange(&mut target, "Z2,3ffb0000,4"), "");
+    }
+
+    #[test]
+    fn resume() {
+        let mut target = MockTarget::new();
+        let (resume, output) = run(&mut target, &[&packet("c")]);
+        assert_eq!(resume, Resume::Continue(None));
+        assert_eq!(output, "$S05#b8+");
+        let (resume, _) = run(&mut target, &[&packet("c400d1000")]);
+        assert_eq!(resume, Resume::Continue(Some(0x400d_1000)));
+        let (resume, _) = run(&mut target, &[&packet("s")]);
+        assert_eq!(resume, Resume::Step(None));
+        let (resume, _) = run(&mut target, &[&packet("s400d1003")]);
+        assert_eq!(resume, Resume::Step(Some(0x400d_1003)));
+    }
+}
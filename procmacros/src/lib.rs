@@ -135,16 +135,55 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
 }
 
 /// Marks a function as the exception handler
+///
+/// `#[exception(kernel)]` marks the handler of the exceptions taken through the kernel vector,
+/// i.e. raised with PS.UM cleared. Without it they are passed to the `#[exception]` handler.
 #[proc_macro_attribute]
 pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
-    let mut f = parse_macro_input!(input as ItemFn);
+    let attr_args = match NestedMeta::parse_meta_list(args.into()) {
+        Ok(v) => v,
+        Err(e) => {
+            return TokenStream::from(darling::Error::from(e).write_errors());
+        }
+    };
 
+    let export_name = match attr_args.as_slice() {
+        [] => "__user_exception",
+        [NestedMeta::Meta(syn::Meta::Path(path))] if path.is_ident("kernel") => {
+            "__kernel_exception"
+        }
+        _ => {
+            return parse::Error::new(
+                Span::call_site(),
+                "This attribute accepts no arguments or `kernel`",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    exception_handler(input, "exception", export_name)
+}
+
+/// Marks a function as the double exception handler
+///
+/// On the ESP32 family it runs on an emergency stack, as double exceptions are typically caused
+/// by a stack overflow. The interrupted code can't be resumed: if the handler returns, the fatal
+/// error handler is called.
+#[proc_macro_attribute]
+pub fn double_exception(args: TokenStream, input: TokenStream) -> TokenStream {
     if !args.is_empty() {
         return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
             .to_compile_error()
             .into();
     }
 
+    exception_handler(input, "double_exception", "__double_exception")
+}
+
+fn exception_handler(input: TokenStream, name: &str, export_name: &str) -> TokenStream {
+    let mut f = parse_macro_input!(input as ItemFn);
+
     if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Exception) {
         return error;
     }
@@ -168,7 +207,10 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
     if !valid_signature {
         return parse::Error::new(
             f.span(),
-            "`#[exception]` handlers must have signature `[unsafe] fn([ExceptionCause[, Context]) [-> !]`",
+            format!(
+                "`#[{}]` handlers must have signature `[unsafe] fn([ExceptionCause[, Context]) [-> !]`",
+                name
+            ),
         )
         .to_compile_error()
         .into();
@@ -195,7 +237,7 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
         #(#cfgs)*
         #(#attrs)*
         #[doc(hidden)]
-        #[export_name = "__user_exception"]
+        #[export_name = #export_name]
         #f
     )
     .into()
@@ -207,6 +249,25 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
 /// no entry and exit code to store processor state will be generated.
 /// The user needs to ensure that all registers which are used are saved and restored and that
 /// the proper return instruction is used.
+///
+/// The ESP8266 only has levels 1 to 3: `#[interrupt(2)]` handles the debug exception and
+/// `#[interrupt(3)]` the NMI, like `#[interrupt(6)]` and `#[interrupt(7)]` on the ESP32 family.
+/// `nested` and `fast` are not supported there.
+///
+/// A level 1 handler can be marked `#[interrupt(1, nested)]`: the level 1 interrupts pending on
+/// entry are masked and PS.INTLEVEL is lowered to 0 while the handler runs, so other level 1
+/// interrupts can preempt it. A handler that keeps one of them disabled after it returns has to
+/// disable it with `interrupt::disable`.
+///
+/// A level 2 to 7 handler can be marked `#[interrupt(5, fast)]`: only the address registers, SAR,
+/// EPC1 and the loop and conditional store registers (when present) are saved before calling it,
+/// instead of the full processor state and spilling all register windows. Such handlers only
+/// receive the interrupt level, must not use floating point and should keep their call chains
+/// shallow: every window overflow they cause spills registers of the interrupted code.
+///
+/// The floating point check is best effort: it only rejects handlers mentioning the `f32` or
+/// `f64` types. Float literals without suffix and functions called by the handler that use the FPU
+/// are not detected, and corrupt the FPU registers of the interrupted code.
 #[proc_macro_attribute]
 pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
     let mut f: ItemFn = syn::parse(input).expect("`#[interrupt]` must be applied to a function");
@@ -218,34 +279,40 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
 
-    if attr_args.len() > 1 {
+    if attr_args.len() > 2 {
         return parse::Error::new(
             Span::call_site(),
-            "This attribute accepts zero or 1 arguments",
+            "This attribute accepts zero, 1 or 2 arguments",
         )
         .to_compile_error()
         .into();
     }
 
     let mut level = 1;
-
-    if attr_args.len() == 1 {
-        match &attr_args[0] {
-            NestedMeta::Lit(syn::Lit::Int(lit_int)) => match lit_int.base10_parse::<u32>() {
-                Ok(x) => level = x,
-                Err(_) => {
-                    return parse::Error::new(
-                        Span::call_site(),
-                        "This attribute accepts an integer attribute",
-                    )
-                    .to_compile_error()
-                    .into()
+    let mut nested = false;
+    let mut fast = false;
+
+    for (i, arg) in attr_args.iter().enumerate() {
+        match arg {
+            NestedMeta::Lit(syn::Lit::Int(lit_int)) if i == 0 => {
+                match lit_int.base10_parse::<u32>() {
+                    Ok(x) => level = x,
+                    Err(_) => {
+                        return parse::Error::new(
+                            Span::call_site(),
+                            "This attribute accepts an integer attribute",
+                        )
+                        .to_compile_error()
+                        .into()
+                    }
                 }
-            },
+            }
+            NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("nested") => nested = true,
+            NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("fast") => fast = true,
             _ => {
                 return parse::Error::new(
                     Span::call_site(),
-                    "This attribute accepts an integer attribute",
+                    "This attribute accepts an integer attribute, optionally followed by `nested` or `fast`",
                 )
                 .to_compile_error()
                 .into()
@@ -261,6 +328,8 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
 
     let ident_s = if naked {
         format!("__naked_level_{}_interrupt", level)
+    } else if fast {
+        format!("__fast_level_{}_interrupt", level)
     } else {
         format!("__level_{}_interrupt", level)
     };
@@ -281,10 +350,41 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         .into();
     }
 
+    if nested && (naked || level != 1) {
+        return parse::Error::new(
+            f.span(),
+            "`nested` `#[interrupt]` handlers must have interrupt level 1 and cannot be `#[naked]`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if fast && (nested || naked || level < 2) {
+        return parse::Error::new(
+            f.span(),
+            "`fast` `#[interrupt]` handlers must have interrupt level >=2 and <=7 and cannot be `#[naked]`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if fast {
+        if let Some(span) = find_float(quote!(#f)) {
+            return parse::Error::new(
+                span,
+                "`fast` `#[interrupt]` handlers cannot use floating point: the FPU registers are not saved",
+            )
+            .to_compile_error()
+            .into();
+        }
+    }
+
     let valid_signature = f.sig.constness.is_none()
         && f.vis == Visibility::Inherited
         && f.sig.abi.is_none()
-        && ((!naked && f.sig.inputs.len() <= 2) || (naked && f.sig.inputs.len() == 0))
+        && ((!naked && !fast && f.sig.inputs.len() <= 2)
+            || (fast && f.sig.inputs.len() <= 1)
+            || (naked && f.sig.inputs.len() == 0))
         && f.sig.generics.params.is_empty()
         && f.sig.generics.where_clause.is_none()
         && f.sig.variadic.is_none()
@@ -298,7 +398,14 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         };
 
     if !valid_signature {
-        if naked {
+        if fast {
+            return parse::Error::new(
+                f.span(),
+                "`fast` `#[interrupt]` handlers must have signature `[unsafe] fn([u32]) [-> !]`",
+            )
+            .to_compile_error()
+            .into();
+        } else if naked {
             return parse::Error::new(
                 f.span(),
                 "`#[naked]` `#[interrupt]` handlers must have signature `[unsafe] fn() [-> !]`",
@@ -380,6 +487,45 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
             #f
         )
         .into()
+    } else if fast {
+        quote!(
+            #(#cfgs)*
+            #(#attrs)*
+            #[doc(hidden)]
+            #[export_name = #ident_s]
+            pub unsafe extern "C" fn #tramp_ident(level: u32) {
+                #ident(#(#args,)*
+                    #(#resource_args),*
+                )
+            }
+
+            #[allow(clippy::inline_always)]
+            #[inline(always)]
+            #f
+        )
+        .into()
+    } else if nested {
+        quote!(
+            #(#cfgs)*
+            #(#attrs)*
+            #[doc(hidden)]
+            #[export_name = #ident_s]
+            pub unsafe extern "C" fn #tramp_ident(
+                level: u32,
+                frame: xtensa_lx_rt::exception::Context
+            ) {
+                xtensa_lx_rt::interrupt::__nested_level_1_interrupt(move || {
+                    #ident(#(#args,)*
+                        #(#resource_args),*
+                    )
+                })
+            }
+
+            #[allow(clippy::inline_always)]
+            #[inline(always)]
+            #f
+        )
+        .into()
     } else {
         quote!(
             #(#cfgs)*
@@ -390,7 +536,7 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
                 level: u32,
                 frame: xtensa_lx_rt::exception::Context
             ) {
-                    #ident(#(#args),*
+                    #ident(#(#args,)*
                     #(#resource_args),*
                 )
             }
@@ -458,6 +604,302 @@ pub fn pre_init(args: TokenStream, input: TokenStream) -> TokenStream {
     .into()
 }
 
+/// Marks a function as the idle hook, called by `xtensa_lx_rt::idle::idle` right before the core
+/// goes to sleep and right after it woke up again.
+///
+/// The function must have signature `[unsafe] fn(xtensa_lx_rt::idle::IdlePhase)` and is called
+/// with the interrupts up to `PS_INTLEVEL_EXCM` masked.
+#[proc_macro_attribute]
+pub fn idle(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(input as ItemFn);
+
+    // check the function signature
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() == 1
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+            ReturnType::Default => true,
+            ReturnType::Type(_, ref ty) => match **ty {
+                Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+                _ => false,
+            },
+        };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[idle]` function must have signature `[unsafe] fn(IdlePhase)`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Idle) {
+        return error;
+    }
+
+    let attrs = f.attrs;
+    let unsafety = f.sig.unsafety;
+    let ident = f.sig.ident;
+    let inputs = f.sig.inputs;
+    let block = f.block;
+
+    quote!(
+        #[export_name = "__idle_hook"]
+        #[allow(missing_docs)]  // we make a private fn public, which can trigger this lint
+        #(#attrs)*
+        pub #unsafety fn #ident(#inputs) #block
+    )
+    .into()
+}
+
+/// Marks a function as the fatal error handler, which decides what happens after an exception
+/// without handler, a double exception or a spurious interrupt.
+///
+/// The function must have signature
+/// `[unsafe] fn(&xtensa_lx_rt::fatal::Fatal) -> xtensa_lx_rt::fatal::FatalAction`.
+#[proc_macro_attribute]
+pub fn fatal_handler(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(input as ItemFn);
+
+    // check the function signature
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() == 1
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+            ReturnType::Default => false,
+            ReturnType::Type(_, ref ty) => match **ty {
+                Type::Tuple(ref tuple) => !tuple.elems.is_empty(),
+                Type::Never(_) => false,
+                _ => true,
+            },
+        };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[fatal_handler]` function must have signature `[unsafe] fn(&Fatal) -> FatalAction`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::FatalHandler) {
+        return error;
+    }
+
+    let attrs = f.attrs;
+    let unsafety = f.sig.unsafety;
+    let ident = f.sig.ident;
+    let inputs = f.sig.inputs;
+    let output = f.sig.output;
+    let block = f.block;
+
+    quote!(
+        #[export_name = "__fatal_handler"]
+        #[allow(missing_docs)]  // we make a private fn public, which can trigger this lint
+        #(#attrs)*
+        pub #unsafety fn #ident(#inputs) #output #block
+    )
+    .into()
+}
+
+/// Marks a function as the interrupt fault hook, called when the runtime finds a spurious or
+/// stuck interrupt.
+///
+/// The function must have signature `[unsafe] fn(xtensa_lx_rt::interrupt::InterruptFault)` and is
+/// called from the interrupt handler of the faulting interrupt's level.
+#[proc_macro_attribute]
+pub fn interrupt_fault(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(input as ItemFn);
+
+    // check the function signature
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() == 1
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+            ReturnType::Default => true,
+            ReturnType::Type(_, ref ty) => match **ty {
+                Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+                _ => false,
+            },
+        };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[interrupt_fault]` function must have signature `[unsafe] fn(InterruptFault)`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::InterruptFault) {
+        return error;
+    }
+
+    let attrs = f.attrs;
+    let unsafety = f.sig.unsafety;
+    let ident = f.sig.ident;
+    let inputs = f.sig.inputs;
+    let block = f.block;
+
+    quote!(
+        #[export_name = "__interrupt_fault_hook"]
+        #[allow(missing_docs)]  // we make a private fn public, which can trigger this lint
+        #(#attrs)*
+        pub #unsafety fn #ident(#inputs) #block
+    )
+    .into()
+}
+
+/// Marks a function as the handler of system call `n`, see `xtensa_lx_rt::syscall`
+///
+/// The function must have signature `fn([u32; 5]) -> u32`: it gets the caller's A3-A7 and its
+/// result is returned in A2.
+#[proc_macro_attribute]
+pub fn syscall(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(input as ItemFn);
+
+    let number = match syn::parse::<syn::LitInt>(args).and_then(|lit| lit.base10_parse::<u32>()) {
+        Ok(number) => number,
+        Err(_) => {
+            return parse::Error::new(
+                Span::call_site(),
+                "This attribute accepts the syscall number as integer argument",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    // check the function signature
+    let valid_signature = f.sig.constness.is_none()
+        && f.sig.unsafety.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() == 1
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none();
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[syscall]` function must have signature `fn([u32; 5]) -> u32`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    // check the argument and return types, pointing at the offending one
+    let (span, valid_type) = match &f.sig.inputs[0] {
+        FnArg::Typed(arg) => (arg.ty.span(), is_u32_array(&arg.ty, 5)),
+        FnArg::Receiver(receiver) => (receiver.span(), false),
+    };
+    if !valid_type {
+        return parse::Error::new(span, "`#[syscall]` function argument must be `[u32; 5]`")
+            .to_compile_error()
+            .into();
+    }
+
+    let (span, valid_type) = match &f.sig.output {
+        ReturnType::Type(_, ty) => (ty.span(), is_u32(ty)),
+        ReturnType::Default => (f.sig.paren_token.span.close(), false),
+    };
+    if !valid_type {
+        return parse::Error::new(span, "`#[syscall]` function must return `u32`")
+            .to_compile_error()
+            .into();
+    }
+
+    if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Syscall) {
+        return error;
+    }
+
+    let ident = &f.sig.ident;
+
+    quote!(
+        #f
+
+        const _: () = {
+            #[used]
+            #[link_section = ".syscalls"]
+            static ENTRY: xtensa_lx_rt::syscall::SyscallEntry = xtensa_lx_rt::syscall::SyscallEntry {
+                number: #number,
+                handler: #ident,
+            };
+        };
+    )
+    .into()
+}
+
+/// Whether `ty` is `u32`
+fn is_u32(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path.qself.is_none() && path.path.is_ident("u32"),
+        Type::Group(group) => is_u32(&group.elem),
+        Type::Paren(paren) => is_u32(&paren.elem),
+        _ => false,
+    }
+}
+
+/// Whether `ty` is `[u32; len]`
+fn is_u32_array(ty: &Type, len: usize) -> bool {
+    match ty {
+        Type::Array(array) => {
+            is_u32(&array.elem)
+                && matches!(
+                    &array.len,
+                    syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(int), .. })
+                        if int.base10_parse::<usize>().ok() == Some(len)
+                )
+        }
+        Type::Group(group) => is_u32_array(&group.elem, len),
+        Type::Paren(paren) => is_u32_array(&paren.elem, len),
+        _ => false,
+    }
+}
+
+/// Returns the span of the first `f32` or `f64` mentioned in the given tokens
+fn find_float(tokens: proc_macro2::TokenStream) -> Option<Span> {
+    tokens.into_iter().find_map(|tt| match tt {
+        proc_macro2::TokenTree::Ident(ident) if ident == "f32" || ident == "f64" => Some(ident.span()),
+        proc_macro2::TokenTree::Group(group) => find_float(group.stream()),
+        _ => None,
+    })
+}
+
 /// Extracts `static mut` vars from the beginning of the given statements
 fn extract_static_muts(
     stmts: impl IntoIterator<Item = Stmt>,
@@ -518,6 +960,10 @@ enum WhiteListCaller {
     Exception,
     Interrupt,
     PreInit,
+    Idle,
+    InterruptFault,
+    FatalHandler,
+    Syscall,
 }
 
 fn check_attr_whitelist(attrs: &[Attribute], caller: WhiteListCaller) -> Result<(), TokenStream> {
@@ -555,6 +1001,18 @@ fn check_attr_whitelist(attrs: &[Attribute], caller: WhiteListCaller) -> Result<
             WhiteListCaller::PreInit => {
                 "this attribute is not allowed on a pre-init controlled by xtensa-lx-rt"
             }
+            WhiteListCaller::Idle => {
+                "this attribute is not allowed on an idle hook controlled by xtensa-lx-rt"
+            }
+            WhiteListCaller::Syscall => {
+                "this attribute is not allowed on a syscall handler controlled by xtensa-lx-rt"
+            }
+            WhiteListCaller::FatalHandler => {
+                "this attribute is not allowed on a fatal error handler controlled by xtensa-lx-rt"
+            }
+            WhiteListCaller::InterruptFault => {
+                "this attribute is not allowed on an interrupt fault hook controlled by xtensa-lx-rt"
+            }
         };
 
         return Err(parse::Error::new(attr.span(), &err_str)
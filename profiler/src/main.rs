@@ -0,0 +1,196 @@
+//! Fold the samples of the `xtensa-lx-rt` profiler into stacks for flamegraphs.
+//!
+//! Usage: `xtensa-lx-rt-profiler <elf> [log]`
+//!
+//! Reads the `PROF <pc> <return address>...` lines printed by the device from `log`, or from
+//! stdin, and writes one `outer;...;inner <count>` line per distinct stack to stdout. Other lines
+//! of the log are ignored. The output can be passed to `inferno-flamegraph` or `flamegraph.pl`.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::{env, fs};
+
+use addr2line::object::{self, Object};
+use anyhow::{bail, Context as _, Result};
+
+/// Size of the `call4`/`callx4` instructions a return address follows
+const CALL_SIZE: u64 = 3;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let (elf, log) = match args.as_slice() {
+        [_, elf] => (elf, None),
+        [_, elf, log] => (elf, Some(log)),
+        _ => bail!("Usage: xtensa-lx-rt-profiler <elf> [log]"),
+    };
+
+    let data = fs::read(elf).with_context(|| format!("Unable to read {}", elf))?;
+    let object = object::File::parse(&*data).context("Unable to parse the ELF file")?;
+    let symbols = Symbolizer::new(&object)?;
+
+    let input: Box<dyn BufRead> = match log {
+        Some(log) => Box::new(BufReader::new(
+            fs::File::open(log).with_context(|| format!("Unable to open {}", log))?,
+        )),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let stacks = fold(input, |address| symbols.frames(address))?;
+
+    let mut stdout = io::stdout().lock();
+    for (stack, count) in stacks {
+        writeln!(stdout, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+/// Count the distinct stacks of the `PROF` lines of `log`, `symbolize` returns the function names
+/// at an address like [`Symbolizer::frames`]
+fn fold(
+    log: impl BufRead,
+    mut symbolize: impl FnMut(u64) -> Result<Vec<String>>,
+) -> Result<BTreeMap<String, u64>> {
+    let mut stacks = BTreeMap::<String, u64>::new();
+    for line in log.lines() {
+        let line = line?;
+        let addresses = match parse_sample(&line) {
+            Some(addresses) => addresses,
+            None => continue,
+        };
+
+        let mut frames = Vec::new();
+        for (i, address) in addresses.into_iter().enumerate() {
+            // return addresses point behind the call, look up the call itself
+            let address = if i == 0 {
+                address
+            } else {
+                match address.checked_sub(CALL_SIZE) {
+                    Some(address) => address,
+                    // can't follow a call, e.g. the end of a corrupted stack
+                    None => continue,
+                }
+            };
+            frames.extend(symbolize(address)?);
+        }
+        frames.reverse();
+        *stacks.entry(frames.join(";")).or_default() += 1;
+    }
+    Ok(stacks)
+}
+
+/// The addresses of a `PROF` line, innermost first
+fn parse_sample(line: &str) -> Option<Vec<u64>> {
+    // the line may carry a prefix of the logger
+    let (_, sample) = line.split_once("PROF ")?;
+    sample
+        .split_whitespace()
+        .map(|address| u64::from_str_radix(address, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|addresses| !addresses.is_empty())
+}
+
+struct Symbolizer<'data> {
+    context: addr2line::ObjectContext,
+    symbols: object::SymbolMap<object::SymbolMapName<'data>>,
+}
+
+impl<'data> Symbolizer<'data> {
+    fn new(object: &object::File<'data>) -> Result<Self> {
+        Ok(Symbolizer {
+            context: addr2line::Context::new(object).context("Unable to load the debug info")?,
+            symbols: object.symbol_map(),
+        })
+    }
+
+    /// The function names at `address`, outermost inlined function last
+    fn frames(&self, address: u64) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut frames = self.context.find_frames(address).skip_all_loads()?;
+        while let Some(frame) = frames.next()? {
+            if let Some(function) = frame.function {
+                names.push(function.demangle()?.into_owned());
+            }
+        }
+
+        // no debug info, fall back to the symbol table
+        if names.is_empty() {
+            let symbol = self
+                .symbols
+                .get(address)
+                .map(|symbol| addr2line::demangle_auto(symbol.name().into(), None).into_owned());
+            names.push(symbol.unwrap_or_else(|| format!("{:#010x}", address)));
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+I (312) boot: entry 0x40080404
+PROF 400d1010 400d2023 400d3033
+[00:00:01] PROF 400d1014 400d2023 400d3033
+PROF 400d2008 400d3033
+PROF 400d1010 400d2023 400d3033
+PROF
+PROF 400d1010 xyz
+PROF 400d2008 2
+";
+
+    /// `f<n>` for the function at `0x400d<n>000`, an inlined `inner` at `0x400d1010`
+    fn symbolize(address: u64) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        if address == 0x400d_1010 {
+            names.push("inner".to_string());
+        }
+        names.push(format!("f{}", (address >> 12) & 0xf));
+        Ok(names)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_sample("PROF 400d1010 400d2023"),
+            Some(vec![0x400d_1010, 0x400d_2023])
+        );
+        assert_eq!(
+            parse_sample("[00:00:01] PROF 400D1010"),
+            Some(vec![0x400d_1010])
+        );
+        assert_eq!(parse_sample("PROF"), None);
+        assert_eq!(parse_sample("PROF "), None);
+        assert_eq!(parse_sample("PROF 400d1010 xyz"), None);
+        assert_eq!(parse_sample("I (312) boot: entry 0x40080404"), None);
+    }
+
+    #[test]
+    fn fold_log() {
+        let mut looked_up = Vec::new();
+        let stacks = fold(LOG.as_bytes(), |address| {
+            looked_up.push(address);
+            symbolize(address)
+        })
+        .unwrap();
+
+        let stacks = stacks
+            .iter()
+            .map(|(stack, count)| (stack.as_str(), *count))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stacks,
+            [
+                ("f2", 1),
+                ("f3;f2", 1),
+                ("f3;f2;f1", 1),
+                ("f3;f2;f1;inner", 2)
+            ]
+        );
+
+        // the return addresses are looked up at their call, the one that can't follow a call is
+        // skipped
+        assert_eq!(looked_up[..3], [0x400d_1010, 0x400d_2020, 0x400d_3030]);
+        assert_eq!(looked_up[looked_up.len() - 1], 0x400d_2008);
+    }
+}
@@ -4,32 +4,59 @@
 //! how many interrupt levels etc.
 //!
 //! First level interrupts and exceptions save full processor state to the user stack.
-//! This includes the coprocessor registers contrary to the esp-idf where these are lazily saved.
-//! (Kernel mode option is currently not used.)
+//! This includes the coprocessor registers contrary to the esp-idf where these are lazily saved,
+//! unless the `lazy-fpu` feature is enabled: handlers then start with the FPU disabled and the
+//! FPU registers are only saved on the first floating point instruction (`Cp0Disabled`).
+//! The ESP32-S3 PIE vector registers are always saved this way (`Cp1Disabled`) when the `pie`
+//! feature is enabled, and not at all otherwise.
+//! Exceptions taken through the kernel vector (PS.UM cleared, e.g. in interrupt and exception
+//! handlers) are passed to the `#[exception(kernel)]` handler, or to the `#[exception]` handler if
+//! there is none.
 //!
 //! WindowUnder/Overflow and AllocA use default Xtensa implementation.
 //!
 //! LoadStoreError and Unaligned are not (yet) implemented: so all accesses to IRAM must
 //! be word sized and aligned.
 //!
-//! Syscall 0 is not (yet) implemented: it doesn't seem to be used in rust.
+//! Syscalls are dispatched to `#[syscall(n)]` handlers with the `syscall` feature, see
+//! [`syscall`](crate::syscall).
 //!
 //! Double Exceptions can only occur during the early setup of the exception handler. Afterwards
 //! PS.EXCM is set to 0 to be able to handle WindowUnderflow/Overflow and recursive exceptions will
-//! happen instead.
+//! happen instead. On the ESP32 family the `#[double_exception]` handler runs on a per core
+//! emergency stack reserved by the linker script (`_emergency_stack_size` bytes, 2048 by default).
+//! With the `stack-guard` feature the stack faults of other handlers are reported from there too,
+//! see [`set_depth_limit`].
 //!
 //! In various places call0 are used as long jump: `j.l` syntax is not supported and `call0`
 //! can always be expanded to `mov a0,label; call a0`. Care must be taken since A0 is overwritten.
 //!
+//! On the ESP8266 (LX106) only the address registers, SAR, PC, PS, EXCCAUSE and EXCVADDR are
+//! saved. Its debug exception and NMI are passed to the `#[interrupt(2)]` and `#[interrupt(3)]`
+//! handlers.
+//!
 
 #[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
 mod assembly_esp32;
 #[cfg(feature = "esp8266")]
 mod assembly_esp8266;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    any(
+        all(XCHAL_HAVE_FP, feature = "lazy-fpu"),
+        all(XCHAL_HAVE_CP1, feature = "pie")
+    )
+))]
+mod coprocessor;
 #[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
 mod esp32;
 #[cfg(feature = "esp8266")]
 mod esp8266;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "stack-guard"
+))]
+mod guard;
 
 /// EXCCAUSE register values
 ///
@@ -38,6 +65,7 @@ mod esp8266;
 ///
 #[allow(unused)]
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
 pub enum ExceptionCause {
     /// Illegal Instruction
@@ -124,7 +152,213 @@ pub enum ExceptionCause {
     None = 255,
 }
 
+impl From<u32> for ExceptionCause {
+    /// The cause with the given EXCCAUSE value, `None` for values without variant
+    fn from(cause: u32) -> Self {
+        if cause <= ExceptionCause::Cp7Disabled as u32 {
+            // The variants from 0 to `Cp7Disabled` are contiguous
+            unsafe { core::mem::transmute::<u32, ExceptionCause>(cause) }
+        } else {
+            ExceptionCause::None
+        }
+    }
+}
+
+/// The vector an exception was taken through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExceptionVector {
+    /// PS.UM was set, handled by `#[exception]`
+    User,
+    /// PS.UM was cleared, handled by `#[exception(kernel)]` if there is one, `#[exception]`
+    /// otherwise
+    Kernel,
+}
+
 #[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
 pub use esp32::Context;
 #[cfg(feature = "esp8266")]
 pub use esp8266::Context;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "stack-guard"
+))]
+pub use guard::{depth, set_depth_limit};
+
+/// Chip independent access to the state saved when entering an exception or interrupt
+///
+/// Implemented by [`Context`], so code inspecting or changing the interrupted state, like crash
+/// reporting, can be written once for every chip.
+pub trait ExceptionFrame {
+    /// The interruptee's PC
+    fn pc(&self) -> u32;
+    /// Changes the PC the interruptee resumes at
+    fn set_pc(&mut self, value: u32);
+    /// The interruptee's PS
+    fn ps(&self) -> u32;
+    /// Address register `n` of the interruptee's current window
+    ///
+    /// Panics if `n` is greater than 15.
+    fn a(&self, n: usize) -> u32;
+    /// Changes address register `n` of the interruptee's current window
+    ///
+    /// Panics if `n` is greater than 15.
+    fn set_a(&mut self, n: usize, value: u32);
+    /// The cause of the exception, only meaningful for exceptions
+    fn cause(&self) -> ExceptionCause;
+    /// The faulting address of memory access exceptions (EXCVADDR)
+    fn fault_addr(&self) -> u32;
+    /// Floating point register `n`, if the chip has an FPU and its registers were saved
+    fn f(&self, n: usize) -> Option<f32> {
+        let _ = n;
+        None
+    }
+    /// The name and value of every saved register, in frame order
+    fn registers(&self) -> Registers<'_>;
+}
+
+/// Iterator over the names and values of the registers of an [`ExceptionFrame`]
+#[derive(Debug, Clone)]
+pub struct Registers<'a> {
+    names: core::slice::Iter<'static, &'static str>,
+    values: core::slice::Iter<'a, u32>,
+}
+
+impl Iterator for Registers<'_> {
+    type Item = (&'static str, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((*self.names.next()?, *self.values.next()?))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.names.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Registers<'_> {}
+
+impl ExceptionFrame for Context {
+    fn pc(&self) -> u32 {
+        self.PC
+    }
+
+    fn set_pc(&mut self, value: u32) {
+        self.PC = value;
+    }
+
+    fn ps(&self) -> u32 {
+        self.PS
+    }
+
+    fn a(&self, n: usize) -> u32 {
+        match n {
+            0 => self.A0,
+            1 => self.A1,
+            2 => self.A2,
+            3 => self.A3,
+            4 => self.A4,
+            5 => self.A5,
+            6 => self.A6,
+            7 => self.A7,
+            8 => self.A8,
+            9 => self.A9,
+            10 => self.A10,
+            11 => self.A11,
+            12 => self.A12,
+            13 => self.A13,
+            14 => self.A14,
+            15 => self.A15,
+            _ => panic!("There are only 16 address registers"),
+        }
+    }
+
+    fn set_a(&mut self, n: usize, value: u32) {
+        *match n {
+            0 => &mut self.A0,
+            1 => &mut self.A1,
+            2 => &mut self.A2,
+            3 => &mut self.A3,
+            4 => &mut self.A4,
+            5 => &mut self.A5,
+            6 => &mut self.A6,
+            7 => &mut self.A7,
+            8 => &mut self.A8,
+            9 => &mut self.A9,
+            10 => &mut self.A10,
+            11 => &mut self.A11,
+            12 => &mut self.A12,
+            13 => &mut self.A13,
+            14 => &mut self.A14,
+            15 => &mut self.A15,
+            _ => panic!("There are only 16 address registers"),
+        } = value;
+    }
+
+    fn cause(&self) -> ExceptionCause {
+        self.EXCCAUSE.into()
+    }
+
+    fn fault_addr(&self) -> u32 {
+        self.EXCVADDR
+    }
+
+    #[cfg(XCHAL_HAVE_FP)]
+    fn f(&self, n: usize) -> Option<f32> {
+        if !self.fpu_saved() {
+            return None;
+        }
+        Some(f32::from_bits(match n {
+            0 => self.F0,
+            1 => self.F1,
+            2 => self.F2,
+            3 => self.F3,
+            4 => self.F4,
+            5 => self.F5,
+            6 => self.F6,
+            7 => self.F7,
+            8 => self.F8,
+            9 => self.F9,
+            10 => self.F10,
+            11 => self.F11,
+            12 => self.F12,
+            13 => self.F13,
+            14 => self.F14,
+            15 => self.F15,
+            _ => return None,
+        }))
+    }
+
+    fn registers(&self) -> Registers<'_> {
+        // `Context` only has `u32` fields, one for each name
+        let values = unsafe {
+            core::slice::from_raw_parts(self as *const Context as *const u32, Context::NAMES.len())
+        };
+        Registers {
+            names: Context::NAMES.iter(),
+            values: values.iter(),
+        }
+    }
+}
+
+impl Context {
+    /// The registers included in a fatal error dump
+    pub(crate) fn dump_registers(&self) -> [(&'static str, u32); 20] {
+        const A: [&str; 16] = [
+            "A0", "A1", "A2", "A3", "A4", "A5", "A6", "A7", "A8", "A9", "A10", "A11", "A12", "A13",
+            "A14", "A15",
+        ];
+
+        let mut registers = [("", 0); 20];
+        registers[..4].copy_from_slice(&[
+            ("PC", self.pc()),
+            ("PS", self.ps()),
+            ("EXCCAUSE", self.EXCCAUSE),
+            ("EXCVADDR", self.fault_addr()),
+        ]);
+        for (n, name) in A.iter().enumerate() {
+            registers[4 + n] = (name, self.a(n));
+        }
+        registers
+    }
+}
@@ -1,108 +1,142 @@
-use crate::cfg_asm;
-use core::arch::{asm, global_asm};
+use crate::cfg_global_asm;
+use core::arch::global_asm;
+
+// The offsets of the exception frame (`XT_STK_*`) are generated from the same layout as
+// `Context`, registers of options the chip doesn't have are left out. The frame size is fixed:
+// in order to conveniently use `addmi` we need 256-byte alignment anyway. Additionally there is a
+// chunk of memory reserved for spilled registers at the end of the frame.
+// With the `compact-frames` feature the frame is only rounded up to 16 bytes instead, which saves
+// stack for every nesting level at the cost of an instruction or two to adjust the stack pointer.
+include!(concat!(env!("OUT_DIR"), "/exception_frame.rs"));
+
+// The offsets of the PIE registers in a save area (`XT_CP1_SA_*`) are generated from the chip's
+// `XCHAL_CP1_SA_LIST`, the same layout `save_pie` stores them in.
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+include!(concat!(env!("OUT_DIR"), "/coprocessor_save_area.rs"));
 
-// we could cfg symbols away and reduce frame size depending on features enabled
-// i.e the frame size is a fixed size based on all the features right now
-// we know at compile time if a target has loops for example, if it doesn't
-// we could cut that memory usage.
-// However in order to conveniently use `addmi` we need 256-byte alignment anyway
-// so wasting a bit more stack space seems to be the better option.
-// Additionally there is a chunk of memory reserved for spilled registers.
 global_asm!(
-    "
-    .set XT_STK_PC,              0 
-    .set XT_STK_PS,              4 
-    .set XT_STK_A0,              8 
-    .equ XT_STK_A1,             12 
-    .set XT_STK_A2,             16 
-    .set XT_STK_A3,             20 
-    .set XT_STK_A4,             24 
-    .set XT_STK_A5,             28 
-    .set XT_STK_A6,             32 
-    .set XT_STK_A7,             36 
-    .set XT_STK_A8,             40 
-    .set XT_STK_A9,             44 
-    .set XT_STK_A10,            48 
-    .set XT_STK_A11,            52 
-    .set XT_STK_A12,            56 
-    .set XT_STK_A13,            60 
-    .set XT_STK_A14,            64 
-    .set XT_STK_A15,            68 
-    .set XT_STK_SAR,            72 
-    .set XT_STK_EXCCAUSE,       76
-    .set XT_STK_EXCVADDR,       80
-    .set XT_STK_LBEG,           84 // Registers for Loop Option
-    .set XT_STK_LEND,           88
-    .set XT_STK_LCOUNT,         92
-    .set XT_STK_THREADPTR,      96 // freely usable 32-bit register intended for TLS
-    .set XT_STK_SCOMPARE1,     100 // Register for s32ci instruction
-    .set XT_STK_BR,            104 // Register for Boolean Option
-    .set XT_STK_ACCLO,         108 // Registers for MAC16 option
-    .set XT_STK_ACCHI,         112
-    .set XT_STK_M0,            116
-    .set XT_STK_M1,            120
-    .set XT_STK_M2,            124
-    .set XT_STK_M3,            128
-    .set XT_STK_F64R_LO,       132 // Registers for double support option
-    .set XT_STK_F64R_HI,       136
-    .set XT_STK_F64S,          140
-    .set XT_STK_FCR,           144 // Registers for floating point coprocessor
-    .set XT_STK_FSR,           148
-    .set XT_STK_F0,            152
-    .set XT_STK_F1,            156
-    .set XT_STK_F2,            160
-    .set XT_STK_F3,            164
-    .set XT_STK_F4,            168
-    .set XT_STK_F5,            172
-    .set XT_STK_F6,            176
-    .set XT_STK_F7,            180
-    .set XT_STK_F8,            184
-    .set XT_STK_F9,            188
-    .set XT_STK_F10,           192
-    .set XT_STK_F11,           196
-    .set XT_STK_F12,           200
-    .set XT_STK_F13,           204
-    .set XT_STK_F14,           208
-    .set XT_STK_F15,           212
-    .set XT_STK_TMP,           216
-
-    .set XT_STK_FRMSZ,         256      // needs to be multiple of 16 and enough additional free space
-                                        // for the registers spilled to the stack (max 8 registers / 0x20 bytes)
-                                        // multiple of 256 allows use of addmi instruction
+    r#"
+    .set XT_STK_TMP,            XT_STK_CPFRAME      // scratch of save_context until the frame is linked
 
+    .set XT_STK_FAST_EPC1,      XT_STK_EXCCAUSE     // fast interrupt frames have no cause, reuse
+    .set XT_STK_FAST_SCOMPARE1, XT_STK_EXCVADDR     // these slots to keep the frame small
 
+    // XT_STK_FAST_FRMSZ is generated with the layout, which checks that the fast frame still has
+    // room for the registers spilled to the stack (max 8 registers / 0x20 bytes)
+
+    .set XT_CP_SAVED_SHIFT, 8
 
     .set PS_INTLEVEL_EXCM, 3	        // interrupt handlers above this level shouldn't be written in high level languages
     .set PS_INTLEVEL_MASK, 0x0000000f
     .set PS_EXCM,          0x00000010
     .set PS_UM,            0x00000020
     .set PS_WOE,           0x00040000
-    "
+
+    // reg = src + amount, for the frame sizes: addmi for multiples of 256, otherwise up to two addi
+    .macro ADD_FRAME reg:req, src:req, amount:req
+    .if ((\amount) >= -128) && ((\amount) <= 127)
+    addi    \reg, \src, \amount
+    .elseif ((\amount) % 256) == 0
+    addmi   \reg, \src, \amount
+    .else
+    addi    \reg, \src, (\amount) / 2
+    addi    \reg, \reg, (\amount) - (\amount) / 2
+    .endif
+    .endm
+    "#
+);
+
+// Coprocessors saved lazily (CP0 = FPU with `lazy-fpu`, CP1 = PIE with `pie`)
+#[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+global_asm!(".set XT_CP_LAZY_CP0, 0x01");
+#[cfg(not(all(XCHAL_HAVE_FP, feature = "lazy-fpu")))]
+global_asm!(".set XT_CP_LAZY_CP0, 0");
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+global_asm!(".set XT_CP_LAZY_CP1, 0x02");
+#[cfg(not(all(XCHAL_HAVE_CP1, feature = "pie")))]
+global_asm!(".set XT_CP_LAZY_CP1, 0");
+global_asm!(".set XT_CP_LAZY_MASK, XT_CP_LAZY_CP0 | XT_CP_LAZY_CP1");
+
+// The handlers below are defined in global assembly rather than as naked functions, so they can
+// carry their own call frame information (`.cfi_*`). This lets a debugger unwind from a handler
+// into the interruptee through the frame the handler pushed.
+//
+// The DWARF register numbers of A0-A15 are 0-15. PC and PS have no numbers of their own, they use
+// the ones GDB assigns to them on Xtensa (0x20 and 0x2e6). The interruptee's PC is the return
+// address column of the handlers.
+global_asm!(
+    r#"
+    // Start the function `name` in .rwtext
+    .macro FUNCTION name:req
+    .pushsection .rwtext,"ax",@progbits
+    .global \name
+    .type   \name,@function
+    .align  4
+    \name:
+    .cfi_startproc
+    .cfi_def_cfa 1, 0
+    .endm
+
+    .macro END_FUNCTION name:req
+    .cfi_endproc
+    .size   \name, . - \name
+    .popsection
+    .endm
+
+    // Start the function `name` that is entered from an exception or interrupt vector. Until
+    // the interruptee's PC is saved to the frame it can't be unwound.
+    .macro HANDLER name:req
+    FUNCTION \name
+    .cfi_signal_frame
+    .cfi_return_column 0x20
+    .cfi_undefined 0x20                // PC is in EPCn
+    .cfi_undefined 0                   // A0 is in EXCSAVEn
+    .endm
+
+    // The interruptee's `reg` is saved to the slot `offset` of the frame of size `size` at A1
+    .macro CFI_SAVED reg:req, offset:req, size:req
+    .cfi_offset \reg, \offset - \size
+    .endm
+
+    // Frames on the emergency stack can't be found from the interruptee's A1, they are described
+    // relative to the frame at A1 with DWARF expressions (DW_OP_breg1 offset):
+    // the CFA is the interruptee's A1 saved in the frame (DW_CFA_def_cfa_expression, DW_OP_deref)
+    .macro CFI_EMERGENCY_CFA
+    .cfi_escape 0x0f, 0x03, 0x71, XT_STK_A1, 0x06
+    .endm
+
+    // the interruptee's `reg` is saved to the slot `offset` of the frame (DW_CFA_expression)
+    .macro CFI_EMERGENCY_SAVED reg:req, offset:req
+    .if \reg < 0x80
+    .cfi_escape 0x10, \reg, 0x02, 0x71, \offset
+    .else
+    .cfi_escape 0x10, (\reg & 0x7f) | 0x80, \reg >> 7, 0x02, 0x71, \offset
+    .endif
+    .endm
+    "#
 );
 
-/// Save processor state to stack.
-///
-/// *Must only be called with call0.*
-/// *For spill all window registers to work WOE must be enabled on entry
-///
-/// Saves all registers except PC, PS, A0, A1
-///
-/// Inputs:
-///     A0 is the return address
-///     A1 is the stack pointers
-///     Exceptions are disabled (PS.EXCM = 1)
-///
-/// Output:
-///     A0 is the return address
-///     A1 is the stack pointer
-///     A3, A9 are used as scratch registers
-///     EPC1 is changed
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn save_context() {
-    cfg_asm!(
+// Save processor state to stack.
+//
+// *Must only be called with call0.*
+// *For spill all window registers to work WOE must be enabled on entry
+//
+// Saves all registers except PC, PS, A0, A1
+//
+// Inputs:
+//     A0 is the return address
+//     A1 is the stack pointers
+//     Exceptions are disabled (PS.EXCM = 1)
+//
+// Output:
+//     A0 is the return address
+//     A1 is the stack pointer
+//     A2, A3, A9 are used as scratch registers
+//     EPC1 is changed
+//     CPENABLE is changed with the `lazy-fpu` and `pie` features
+cfg_global_asm!(
     {
+        "FUNCTION save_context",
         "
         s32i    a2,  sp, +XT_STK_A2
         s32i    a3,  sp, +XT_STK_A3
@@ -176,7 +210,7 @@ unsafe extern "C" fn save_context() {
         rur     a3, f64s   
         s32i    a3, sp, +XT_STK_F64S
         ",
-        #[cfg(XCHAL_HAVE_FP)]
+        #[cfg(all(XCHAL_HAVE_FP, not(feature = "lazy-fpu")))]
         "
         // Coprocessor Option
         rur     a3, fcr
@@ -203,6 +237,7 @@ unsafe extern "C" fn save_context() {
         #[cfg(XCHAL_HAVE_WINDOWED)]
         "
         s32i    a0, sp, +XT_STK_TMP        // keep return address on the stack
+        .cfi_offset 0, XT_STK_TMP
 
         // SPILL_REGISTERS macro requires window overflow exceptions to be enabled,
         // i.e. PS.EXCM cleared and PS.WOE set.
@@ -230,23 +265,50 @@ unsafe extern "C" fn save_context() {
         wsr     a3, ps
         rsr     a0, EPC1
         
-        addmi   sp,  sp, +XT_STK_FRMSZ   // go back to spill register region
+        .cfi_remember_state
+        .cfi_undefined 0                 // A1 isn't the frame while the window rotates
+        ADD_FRAME sp, sp, +XT_STK_FRMSZ  // go back to spill register region
         SPILL_REGISTERS
-        addmi   sp,  sp, -XT_STK_FRMSZ   // return the current stack pointer
+        ADD_FRAME sp, sp, -XT_STK_FRMSZ  // return the current stack pointer
+        .cfi_restore_state
         
         wsr     a2, PS                   //  restore to the value at entry
         rsync
         wsr     a0, EPC1
 
         l32i    a0,  sp, +XT_STK_TMP
+        .cfi_restore 0
+        ",
+        #[cfg(any(
+            all(XCHAL_HAVE_FP, feature = "lazy-fpu"),
+            all(XCHAL_HAVE_CP1, feature = "pie")
+        ))]
+        "
+        // Lazy Coprocessor Option
+        // The handler runs with the lazily saved coprocessors disabled. This frame becomes the
+        // innermost one of this core, so the CpNDisabled handler knows where to put the live
+        // coprocessor registers once the handler needs them.
+        rsr     a3, CPENABLE
+        s32i    a3, sp, +XT_STK_CPENABLE
+        movi    a2, ~XT_CP_LAZY_MASK
+        and     a3, a3, a2
+        wsr     a3, CPENABLE
+        rsync
+
+        rsr     a3, PRID
+        extui   a3, a3, 13, 1              // core id
+        movi    a2, __xtensa_lx_rt_cp_frames
+        addx4   a2, a3, a2
+        l32i    a3, a2, 0
+        s32i    a3, sp, +XT_STK_CPFRAME    // link the previous innermost frame
+        s32i    sp, a2, 0
         ",
         "
         ret
         ",
-    },
-    options(noreturn)
-    );
-}
+        "END_FUNCTION save_context",
+    }
+);
 
 global_asm!(
     r#"
@@ -296,13 +358,192 @@ global_asm!(
     "#
 );
 
+// The frame at the top of this core's emergency stack (reserved by the linker script), in A0.
+// Only A0 is used.
+#[cfg(XCHAL_HAVE_PRID)]
+global_asm!(
+    r#"
+    .macro EMERGENCY_FRAME
+    rsr     a0, PRID
+    extui   a0, a0, 13, 1              // core id
+    bnez    a0, 1f
+    movi    a0, _emergency_stack0_top
+    j       2f
+    1:
+    movi    a0, _emergency_stack1_top
+    2:
+    ADD_FRAME a0, a0, -XT_STK_FRMSZ
+    .endm
+    "#
+);
+
+#[cfg(not(XCHAL_HAVE_PRID))]
+global_asm!(
+    r#"
+    .macro EMERGENCY_FRAME
+    movi    a0, _emergency_stack0_top
+    ADD_FRAME a0, a0, -XT_STK_FRMSZ
+    .endm
+    "#
+);
+
+// Save the interruptee's state to the emergency frame, for a `level` 1-7 or `double` exception
+// whose stack can't be trusted. Only PC, PS, A0-A15, SAR, EXCCAUSE and EXCVADDR are saved and the
+// live register windows of the interruptee are dropped instead of being spilled to its stack. All
+// maskable interrupts stay masked.
+global_asm!(
+    r#"
+    .macro SAVE_EMERGENCY_CONTEXT level:req
+    EMERGENCY_FRAME
+    s32i    a1, a0, +XT_STK_A1         // save interruptee's A1/SP
+    mov     a1, a0
+    CFI_EMERGENCY_CFA
+    l32i    a0, sp, +XT_STK_A1
+    s32e    a0, sp, -12                // for debug backtrace
+
+    .ifc \level,2
+    rsr     a0, EPS2
+    .else
+    .ifc \level,3
+    rsr     a0, EPS3
+    .else
+    .ifc \level,4
+    rsr     a0, EPS4
+    .else
+    .ifc \level,5
+    rsr     a0, EPS5
+    .else
+    .ifc \level,6
+    rsr     a0, EPS6
+    .else
+    .ifc \level,7
+    rsr     a0, EPS7
+    .else
+    rsr     a0, PS
+    .endif
+    .endif
+    .endif
+    .endif
+    .endif
+    .endif
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+    CFI_EMERGENCY_SAVED 0x2e6, XT_STK_PS
+
+    rsr     a0, EXCCAUSE
+    s32i    a0, sp, +XT_STK_EXCCAUSE
+    rsr     a0, EXCVADDR
+    s32i    a0, sp, +XT_STK_EXCVADDR
+
+    .ifc \level,double
+    rsr     a0, DEPC
+    .else
+    rsr     a0, EPC\level
+    .endif
+    s32i    a0, sp, +XT_STK_PC         // save interruptee's PC
+    CFI_EMERGENCY_SAVED 0x20, XT_STK_PC
+    s32e    a0, sp, -16                // for debug backtrace
+
+    .ifc \level,double
+    rsr     a0, EXCSAVE1
+    .else
+    rsr     a0, EXCSAVE\level
+    .endif
+    s32i    a0, sp, +XT_STK_A0         // save interruptee's A0
+    CFI_EMERGENCY_SAVED 0, XT_STK_A0
+
+    s32i    a2, sp, +XT_STK_A2
+    s32i    a3, sp, +XT_STK_A3
+    s32i    a4, sp, +XT_STK_A4
+    s32i    a5, sp, +XT_STK_A5
+    s32i    a6, sp, +XT_STK_A6
+    s32i    a7, sp, +XT_STK_A7
+    s32i    a8, sp, +XT_STK_A8
+    s32i    a9, sp, +XT_STK_A9
+    s32i    a10, sp, +XT_STK_A10
+    s32i    a11, sp, +XT_STK_A11
+    s32i    a12, sp, +XT_STK_A12
+    s32i    a13, sp, +XT_STK_A13
+    s32i    a14, sp, +XT_STK_A14
+    s32i    a15, sp, +XT_STK_A15
+    rsr     a2, SAR
+    s32i    a2, sp, +XT_STK_SAR
+
+    // drop the interruptee's register windows, only the current one stays live
+    rsr     a2, WINDOWBASE
+    ssl     a2
+    movi    a2, 1
+    sll     a2, a2
+    wsr     a2, WINDOWSTART
+    rsync
+
+    movi    a0, (PS_INTLEVEL_MASK | PS_WOE) // mask all maskable interrupts, clear EXCM
+    wsr     a0, PS
+    rsync
+    .endm
+    "#
+);
+
+// With the `stack-guard` feature the stack pointer is checked before a frame is pushed to it. If
+// the frame wouldn't fit between `_stack_bounds_start` and `_stack_bounds_end` the handler of the
+// level switches to the emergency stack instead and reports the fatal error.
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    r#"
+    .macro STACK_CHECK level:req
+    movi    a0, _stack_bounds_start + XT_STK_FRMSZ
+    bltu    a1, a0, 8f
+    movi    a0, _stack_bounds_end
+    bgeu    a0, a1, 9f
+    8:
+    call0   __xtensa_lx_rt_stack_fault_\level // used as long jump
+    9:
+    .endm
+
+    .macro DEPTH_ENTER
+    call4   __xtensa_lx_rt_depth_enter
+    beqz    a6, 9f
+    l32i    a6, sp, +XT_STK_EXCCAUSE   // put cause in a6 = a2 in callee
+    mov     a7, sp                     // put address of save frame in a7=a3 in callee
+    EMERGENCY_FRAME
+    .cfi_remember_state
+    mov     sp, a0
+    .cfi_def_cfa 7, XT_STK_FRMSZ       // the frame is in a7 now
+    call4   __xtensa_lx_rt_depth_exceeded // doesn't return
+    .cfi_restore_state
+    9:
+    .endm
+
+    .macro DEPTH_EXIT
+    call4   __xtensa_lx_rt_depth_exit
+    .endm
+    "#
+);
+
+#[cfg(not(feature = "stack-guard"))]
+global_asm!(
+    r#"
+    .macro STACK_CHECK level:req
+    .endm
+
+    .macro DEPTH_ENTER
+    .endm
+
+    .macro DEPTH_EXIT
+    .endm
+    "#
+);
+
 global_asm!(
     r#"
     .macro SAVE_CONTEXT level:req
+    STACK_CHECK \level
+
     mov     a0, a1                     // save a1/sp
-    addmi   sp, sp, -XT_STK_FRMSZ      // only allow multiple of 256
+    ADD_FRAME sp, sp, -XT_STK_FRMSZ
+    .cfi_def_cfa 1, XT_STK_FRMSZ
 
     s32i    a0, sp, +XT_STK_A1         // save interruptee's A1/SP
+    CFI_SAVED 1, XT_STK_A1, XT_STK_FRMSZ
     s32e    a0, sp, -12                // for debug backtrace 
 
     .ifc \level,1
@@ -317,13 +558,16 @@ global_asm!(
     rsr     a0, EPS\level
     s32i    a0, sp, +XT_STK_PS         // save interruptee's PS    
     .endif
+    CFI_SAVED 0x2e6, XT_STK_PS, XT_STK_FRMSZ
 
     rsr     a0, EPC\level                   
     s32i    a0, sp, +XT_STK_PC         // save interruptee's PC 
+    CFI_SAVED 0x20, XT_STK_PC, XT_STK_FRMSZ
     s32e    a0, sp, -16                // for debug backtrace 
 
     rsr     a0, EXCSAVE\level               
     s32i    a0, sp, +XT_STK_A0         // save interruptee's A0 
+    CFI_SAVED 0, XT_STK_A0, XT_STK_FRMSZ
 
     call0   save_context
 
@@ -331,12 +575,128 @@ global_asm!(
     "#
 );
 
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn restore_context() {
-    cfg_asm!(
+cfg_global_asm!(
     {
+        "FUNCTION restore_context",
+        #[cfg(any(
+            all(XCHAL_HAVE_FP, feature = "lazy-fpu"),
+            all(XCHAL_HAVE_CP1, feature = "pie")
+        ))]
+        "
+        // Lazy Coprocessor Option
+        // Unlink this frame, reload the coprocessor registers that were saved into it and restore
+        // the interruptee's CPENABLE
+        rsr     a3, PRID
+        extui   a3, a3, 13, 1              // core id
+        movi    a2, __xtensa_lx_rt_cp_frames
+        addx4   a2, a3, a2
+        l32i    a3, sp, +XT_STK_CPFRAME
+        s32i    a3, a2, 0
+
+        l32i    a3, sp, +XT_STK_CPENABLE
+        ",
+        #[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+        "
+        // PIE registers live in this core's save area pool, the last allocated area belongs to
+        // this frame
+        bbci    a3, XT_CP_SAVED_SHIFT + 1, .LRestoreCp0
+        movi    a2, 2
+        wsr     a2, CPENABLE
+        rsync
+        rsr     a2, PRID
+        extui   a2, a2, 13, 1              // core id
+        movi    a4, __xtensa_lx_rt_cp1_next
+        addx4   a4, a2, a4
+        l32i    a2, a4, 0
+        addi    a2, a2, -XT_CP1_SA_SIZE
+        s32i    a2, a4, 0
+
+        l32i    a4, a2, XT_CP1_SA_ACCX_0
+        wur     a4, accx_0
+        l32i    a4, a2, XT_CP1_SA_ACCX_1
+        wur     a4, accx_1
+        l32i    a4, a2, XT_CP1_SA_QACC_H_0
+        wur     a4, qacc_h_0
+        l32i    a4, a2, XT_CP1_SA_QACC_H_1
+        wur     a4, qacc_h_1
+        l32i    a4, a2, XT_CP1_SA_QACC_H_2
+        wur     a4, qacc_h_2
+        l32i    a4, a2, XT_CP1_SA_QACC_H_3
+        wur     a4, qacc_h_3
+        l32i    a4, a2, XT_CP1_SA_QACC_H_4
+        wur     a4, qacc_h_4
+        l32i    a4, a2, XT_CP1_SA_QACC_L_0
+        wur     a4, qacc_l_0
+        l32i    a4, a2, XT_CP1_SA_QACC_L_1
+        wur     a4, qacc_l_1
+        l32i    a4, a2, XT_CP1_SA_QACC_L_2
+        wur     a4, qacc_l_2
+        l32i    a4, a2, XT_CP1_SA_QACC_L_3
+        wur     a4, qacc_l_3
+        l32i    a4, a2, XT_CP1_SA_QACC_L_4
+        wur     a4, qacc_l_4
+        l32i    a4, a2, XT_CP1_SA_SAR_BYTE
+        wur     a4, sar_byte
+        l32i    a4, a2, XT_CP1_SA_FFT_BIT_WIDTH
+        wur     a4, fft_bit_width
+        l32i    a4, a2, XT_CP1_SA_UA_STATE_0
+        wur     a4, ua_state_0
+        l32i    a4, a2, XT_CP1_SA_UA_STATE_1
+        wur     a4, ua_state_1
+        l32i    a4, a2, XT_CP1_SA_UA_STATE_2
+        wur     a4, ua_state_2
+        l32i    a4, a2, XT_CP1_SA_UA_STATE_3
+        wur     a4, ua_state_3
+        addi    a2, a2, XT_CP1_SA_Q0
+        ee.vld.128.ip q0, a2, 16
+        ee.vld.128.ip q1, a2, 16
+        ee.vld.128.ip q2, a2, 16
+        ee.vld.128.ip q3, a2, 16
+        ee.vld.128.ip q4, a2, 16
+        ee.vld.128.ip q5, a2, 16
+        ee.vld.128.ip q6, a2, 16
+        ee.vld.128.ip q7, a2, 16
+
+        .LRestoreCp0:
+        ",
+        #[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+        "
+        bbci    a3, XT_CP_SAVED_SHIFT, .LRestoreCpEnable
+
+        movi    a2, 1
+        wsr     a2, CPENABLE
+        rsync
+        l32i    a2, sp, +XT_STK_FCR
+        wur     a2, fcr
+        l32i    a2, sp, +XT_STK_FSR
+        wur     a2, fsr
+        lsi     f0, sp, +XT_STK_F0
+        lsi     f1, sp, +XT_STK_F1
+        lsi     f2, sp, +XT_STK_F2
+        lsi     f3, sp, +XT_STK_F3
+        lsi     f4, sp, +XT_STK_F4
+        lsi     f5, sp, +XT_STK_F5
+        lsi     f6, sp, +XT_STK_F6
+        lsi     f7, sp, +XT_STK_F7
+        lsi     f8, sp, +XT_STK_F8
+        lsi     f9, sp, +XT_STK_F9
+        lsi     f10, sp, +XT_STK_F10
+        lsi     f11, sp, +XT_STK_F11
+        lsi     f12, sp, +XT_STK_F12
+        lsi     f13, sp, +XT_STK_F13
+        lsi     f14, sp, +XT_STK_F14
+        lsi     f15, sp, +XT_STK_F15
+        ",
+        #[cfg(any(
+            all(XCHAL_HAVE_FP, feature = "lazy-fpu"),
+            all(XCHAL_HAVE_CP1, feature = "pie")
+        ))]
+        "
+        .LRestoreCpEnable:
+        extui   a3, a3, 0, 8
+        wsr     a3, CPENABLE
+        rsync
+        ",
         "
         l32i    a3,  sp, +XT_STK_SAR
         wsr     a3,  SAR
@@ -395,7 +755,7 @@ unsafe extern "C" fn restore_context() {
         l32i    a3, sp, +XT_STK_F64S
         wur     a3, f64s
         ",
-        #[cfg(XCHAL_HAVE_FP)]
+        #[cfg(all(XCHAL_HAVE_FP, not(feature = "lazy-fpu")))]
         "
         // Coprocessor Option
         l32i    a3, sp, +XT_STK_FCR
@@ -437,8 +797,9 @@ unsafe extern "C" fn restore_context() {
         l32i    a15, sp, +XT_STK_A15
         ret
         ",
-    }, options(noreturn));
-}
+        "END_FUNCTION restore_context",
+    }
+);
 
 global_asm!(
     r#"
@@ -461,126 +822,514 @@ global_asm!(
 
     l32i    a0, sp, +XT_STK_A0        // retrieve interruptee's A0 
     l32i    sp, sp, +XT_STK_A1        // remove exception frame 
+    .cfi_def_cfa 1, 0
+    .cfi_restore 0
+    .cfi_restore 1
+    .cfi_undefined 0x20              // PC is back in EPCn
     rsync                             // ensure PS and EPC written 
     
     .endm
     "#
 );
 
-/// Handle Other Exceptions or Level 1 interrupt by storing full context and then
-/// calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
+// With the `profiler` feature the Profiling interrupt is routed to `Profiling` before the handler
+// of its level is called. The level handler is skipped if no other interrupt of that level is
+// pending.
+#[cfg(all(XCHAL_HAVE_PROFILING, feature = "profiler"))]
+global_asm!(
+    r#"
+    .macro PROFILING_DISPATCH level, skip
+    .if \level == XT_PROFILING_LEVEL
+    rsr     a4, INTERRUPT
+    rsr     a5, INTENABLE
+    and     a4, a4, a5
+    bbci    a4, XT_PROFILING_INTERRUPT, 2f
 
-        movi    a0, (PS_INTLEVEL_EXCM | PS_WOE)
-        wsr     a0, PS
-        rsync
+    movi    a6, \level                     // put interrupt level in a6 = a2 in callee
+    mov     a7, sp                         // put address of save frame in a7=a3 in callee
+    call4   Profiling
 
-        l32i    a6, sp, +XT_STK_EXCCAUSE  // put cause in a6 = a2 in callee
-        beqi    a6, 4, .Level1Interrupt
+    rsr     a4, INTERRUPT
+    rsr     a5, INTENABLE
+    and     a4, a4, a5
+    movi    a5, XT_PROFILING_LEVEL_MASK
+    and     a4, a4, a5
+    beqz    a4, \skip
+    2:
+    .endif
+    .endm
+    "#
+);
 
-        mov     a7, sp                    // put address of save frame in a7=a3 in callee
-        call4   __exception               // call handler <= actual call!
+#[cfg(not(all(XCHAL_HAVE_PROFILING, feature = "profiler")))]
+global_asm!(
+    r#"
+    .macro PROFILING_DISPATCH level, skip
+    .endm
+    "#
+);
 
-        j       .RestoreContext
+// With the `stats` feature the handler calls are timed. STATS_ENTER keeps the start cycles in A2,
+// which survives the call4 to the handler.
+#[cfg(feature = "stats")]
+global_asm!(
+    r#"
+    .macro STATS_ENTER
+    call4   __xtensa_lx_rt_stats_enter
+    rsr     a2, CCOUNT
+    .endm
 
-        .Level1Interrupt:
-        movi    a0, (1 | PS_WOE)          // set PS.INTLEVEL accordingly
-        wsr     a0, PS
-        rsync
+    .macro STATS_EXIT_INTERRUPT level
+    movi    a6, \level
+    mov     a7, a2
+    call4   __xtensa_lx_rt_stats_interrupt_exit
+    .endm
 
-        movi    a6, 1                     // put interrupt level in a6 = a2 in callee
-        mov     a7, sp                    // put address of save frame in a7=a3 in callee
-        call4   __level_1_interrupt       // call handler <= actual call!
+    .macro STATS_EXIT_EXCEPTION
+    l32i    a6, sp, +XT_STK_EXCCAUSE
+    mov     a7, a2
+    call4   __xtensa_lx_rt_stats_exception_exit
+    .endm
+    "#
+);
 
-        .RestoreContext:
-        RESTORE_CONTEXT 1
-        
-        rfe                               // PS.EXCM is cleared 
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle Double Exceptions by storing full context and then calling regular function
-/// Double exceptions are not a normal occurrence. They indicate a bug of some kind.
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_double_exception() {
-    asm!(
-        "
-        mov     a0, a1                     // save a1/sp
-        addmi   sp, sp, -XT_STK_FRMSZ      // only allow multiple of 256
-    
-        s32i    a0, sp, +XT_STK_A1         // save interruptee's A1/SP
-        s32e    a0, sp, -12                // for debug backtrace 
-    
-        rsr     a0, PS                     
-        s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
-    
-        rsr     a0, EXCCAUSE
-        s32i    a0, sp, +XT_STK_EXCCAUSE
-        rsr     a0, EXCVADDR
-        s32i    a0, sp, +XT_STK_EXCVADDR
-    
-        rsr     a0, DEPC                   
-        s32i    a0, sp, +XT_STK_PC         // save interruptee's PC 
-        s32e    a0, sp, -16                // for debug backtrace 
-    
-        rsr     a0, EXCSAVE7               // ok to reuse EXCSAVE7 for double exception as long as
-                                           // double exception is not in first couple of instructions
-                                           // of level 7 handler
-        s32i    a0, sp, +XT_STK_A0         // save interruptee's A0 
+#[cfg(not(feature = "stats"))]
+global_asm!(
+    r#"
+    .macro STATS_ENTER
+    .endm
+
+    .macro STATS_EXIT_INTERRUPT level
+    .endm
+
+    .macro STATS_EXIT_EXCEPTION
+    .endm
+    "#
+);
+
+// With the `semihosting` feature a `break 1, 14` that raised the debug exception, because no
+// host handled it, is completed as failed host call instead of calling the level 6 handler.
+#[cfg(feature = "semihosting")]
+global_asm!(
+    r#"
+    .macro SEMIHOSTING_DISPATCH level, skip
+    .if \level == 6
+    mov     a6, sp                         // put address of save frame in a6 = a2 in callee
+    call4   __xtensa_lx_rt_semihosting_break
+    bnez    a6, \skip
+    .endif
+    .endm
+    "#
+);
+
+#[cfg(not(feature = "semihosting"))]
+global_asm!(
+    r#"
+    .macro SEMIHOSTING_DISPATCH level, skip
+    .endm
+    "#
+);
+
+// With the `gdbstub` feature the debug exception is served by the GDB stub, once it has a
+// transport. The level 6 handler is called otherwise.
+#[cfg(feature = "gdbstub")]
+global_asm!(
+    r#"
+    .macro GDBSTUB_DISPATCH level, skip
+    .if \level == 6
+    mov     a6, sp                         // put address of save frame in a6 = a2 in callee
+    call4   __xtensa_lx_rt_gdbstub_debug
+    bnez    a6, \skip
+    .endif
+    .endm
+    "#
+);
+
+#[cfg(not(feature = "gdbstub"))]
+global_asm!(
+    r#"
+    .macro GDBSTUB_DISPATCH level, skip
+    .endm
+    "#
+);
+
+// With the `storm-detection` feature every entry of an interrupt level is counted against the
+// sources pending at that level. Sources exceeding the limit are disabled, and the handler is
+// skipped if nothing else of the level is pending.
+#[cfg(feature = "storm-detection")]
+global_asm!(
+    r#"
+    .macro STORM_CHECK level, skip
+    movi    a6, \level                     // put interrupt level in a6 = a2 in callee
+    call4   __xtensa_lx_rt_storm_check
+    beqz    a6, \skip                      // nothing left pending at this level
+    .endm
+    "#
+);
+
+#[cfg(not(feature = "storm-detection"))]
+global_asm!(
+    r#"
+    .macro STORM_CHECK level, skip
+    .endm
+    "#
+);
+
+// Handle Other Exceptions or Level 1 interrupt by storing full context and then
+// calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE1
+global_asm!(
+    "
+    HANDLER __default_naked_exception
+
+    SAVE_CONTEXT 1
+
+    movi    a0, (PS_INTLEVEL_EXCM | PS_WOE)
+    wsr     a0, PS
+    rsync
+
+    DEPTH_ENTER
+    STATS_ENTER
+
+    l32i    a6, sp, +XT_STK_EXCCAUSE  // put cause in a6 = a2 in callee
+    beqi    a6, 4, .Level1Interrupt
+
+    mov     a7, sp                    // put address of save frame in a7=a3 in callee
+    call4   __exception               // call handler <= actual call!
+
+    STATS_EXIT_EXCEPTION
+    j       .RestoreContext
+
+    .Level1Interrupt:
+    movi    a0, (1 | PS_WOE)          // set PS.INTLEVEL accordingly
+    wsr     a0, PS
+    rsync
+
+    STORM_CHECK 1, .Level1InterruptDone
+    PROFILING_DISPATCH 1, .Level1InterruptDone
+
+    movi    a6, 1                     // put interrupt level in a6 = a2 in callee
+    mov     a7, sp                    // put address of save frame in a7=a3 in callee
+    call4   __level_1_interrupt       // call handler <= actual call!
+
+    .Level1InterruptDone:
+    STATS_EXIT_INTERRUPT 1
+
+    .RestoreContext:
+    DEPTH_EXIT
+    RESTORE_CONTEXT 1
     
-        call0   save_context
+    rfe                               // PS.EXCM is cleared 
 
-        l32i    a6, sp, +XT_STK_EXCCAUSE  // put cause in a6 = a2 in callee
-        mov     a7, sp                    // put address of save frame in a7=a3 in callee
-        call4   __exception               // call handler <= actual call!
+    END_FUNCTION __default_naked_exception
+    "
+);
 
-        // Restore context and return 
-        call0   restore_context
+// Handle Double Exceptions by storing the interruptee's state on the emergency stack and then
+// calling regular function
+//
+// Double exceptions are not a normal occurrence. They indicate a bug of some kind, typically a
+// stack overflow while entering an exception handler or in a window overflow handler. The stack
+// and the register windows of the interruptee can't be trusted: the frame lives on the emergency
+// stack of this core, only PC, PS, A0-A15, SAR, EXCCAUSE and EXCVADDR are saved and the live
+// register windows of the interruptee are dropped instead of being spilled.
+//
+// The interruptee can't be resumed: when `__double_exception` returns, the fatal error handler
+// is called.
+//
+// # Input:
+//    * A0 stored in EXCSAVE1
+global_asm!(
+    "
+    HANDLER __default_naked_double_exception
 
-        l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS 
-        wsr     a0, PS
-        l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC 
-        wsr     a0, EPC1
+    SAVE_EMERGENCY_CONTEXT double
+
+    l32i    a6, sp, +XT_STK_EXCCAUSE  // put cause in a6 = a2 in callee
+    mov     a7, sp                    // put address of save frame in a7=a3 in callee
+    call4   __double_exception        // call handler <= actual call!
+
+    l32i    a6, sp, +XT_STK_EXCCAUSE
+    mov     a7, sp
+    call4   __default_double_exception // doesn't return
+
+    1:
+    waiti   15
+    j       1b
+
+    END_FUNCTION __default_naked_double_exception
+    "
+);
+
+// Report a stack pointer out of bounds on entry of a level `level` handler, from the emergency
+// stack
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    r#"
+    .macro HANDLE_STACK_FAULT level:req
+    SAVE_EMERGENCY_CONTEXT \level
+
+    movi    a6, \level                   // put interrupt level in a6 = a2 in callee
+    mov     a7, sp                       // put address of save frame in a7=a3 in callee
+    call4   __xtensa_lx_rt_stack_fault   // doesn't return
 
-        l32i    a0, sp, +XT_STK_A0        // retrieve interruptee's A0 
-        l32i    sp, sp, +XT_STK_A1        // remove exception frame 
-        rsync                             // ensure PS and EPC written 
+    1:
+    waiti   15
+    j       1b
+    .endm
+    "#
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_1",
+    "HANDLE_STACK_FAULT 1",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_1"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_2",
+    "HANDLE_STACK_FAULT 2",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_2"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_3",
+    "HANDLE_STACK_FAULT 3",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_3"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_4",
+    "HANDLE_STACK_FAULT 4",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_4"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_5",
+    "HANDLE_STACK_FAULT 5",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_5"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_6",
+    "HANDLE_STACK_FAULT 6",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_6"
+);
+
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    "HANDLER __xtensa_lx_rt_stack_fault_7",
+    "HANDLE_STACK_FAULT 7",
+    "END_FUNCTION __xtensa_lx_rt_stack_fault_7"
+);
+
+// Save the minimal processor state needed by `fast` interrupt handlers to stack.
+//
+// *Must only be called with call0.*
+//
+// Saves A2-A15, SAR, EPC1 and the loop and conditional store registers if present.
+// Register windows are not spilled and coprocessor state is not saved.
+//
+// Inputs:
+//     A0 is the return address
+//     A1 is the stack pointer
+//
+// Output:
+//     A0 is the return address
+//     A1 is the stack pointer
+//     A3 is used as scratch register
+cfg_global_asm!(
+    {
+        "FUNCTION save_fast_context",
+        "
+        s32i    a2,  sp, +XT_STK_A2
+        s32i    a3,  sp, +XT_STK_A3
+        s32i    a4,  sp, +XT_STK_A4
+        s32i    a5,  sp, +XT_STK_A5
+        s32i    a6,  sp, +XT_STK_A6
+        s32i    a7,  sp, +XT_STK_A7
+        s32i    a8,  sp, +XT_STK_A8
+        s32i    a9,  sp, +XT_STK_A9
+        s32i    a10, sp, +XT_STK_A10
+        s32i    a11, sp, +XT_STK_A11
+        s32i    a12, sp, +XT_STK_A12
+        s32i    a13, sp, +XT_STK_A13
+        s32i    a14, sp, +XT_STK_A14
+        s32i    a15, sp, +XT_STK_A15
+
+        rsr     a3,  SAR
+        s32i    a3,  sp, +XT_STK_SAR
+
+        // window exceptions caused by the handler change EPC1
+        rsr     a3,  EPC1
+        s32i    a3,  sp, +XT_STK_FAST_EPC1
+        ",
+        #[cfg(XCHAL_HAVE_LOOPS)]
+        "
+        // Loop Option
+        rsr     a3,  LBEG
+        s32i    a3,  sp, +XT_STK_LBEG
+        rsr     a3,  LEND
+        s32i    a3,  sp, +XT_STK_LEND
+        rsr     a3,  LCOUNT
+        s32i    a3,  sp, +XT_STK_LCOUNT
+        ",
+        #[cfg(XCHAL_HAVE_S32C1I)]
+        "
+        // Conditional Store Option
+        rsr     a3, scompare1
+        s32i    a3, sp, +XT_STK_FAST_SCOMPARE1
+        ",
+        "
+        ret
+        ",
+        "END_FUNCTION save_fast_context",
+    }
+);
 
-        rfde
+// Restore the processor state saved by `save_fast_context`.
+//
+// *Must only be called with call0.*
+cfg_global_asm!(
+    {
+        "FUNCTION restore_fast_context",
+        "
+        l32i    a3,  sp, +XT_STK_SAR
+        wsr     a3,  SAR
+        l32i    a3,  sp, +XT_STK_FAST_EPC1
+        wsr     a3,  EPC1
+        ",
+        #[cfg(XCHAL_HAVE_LOOPS)]
+        "
+        // Loop Option
+        l32i    a3,  sp, +XT_STK_LBEG
+        wsr     a3,  LBEG
+        l32i    a3,  sp, +XT_STK_LEND
+        wsr     a3,  LEND
+        l32i    a3,  sp, +XT_STK_LCOUNT
+        wsr     a3,  LCOUNT
         ",
-        options(noreturn)
-    )
-}
+        #[cfg(XCHAL_HAVE_S32C1I)]
+        "
+        // Conditional Store Option
+        l32i    a3, sp, +XT_STK_FAST_SCOMPARE1
+        wsr     a3, scompare1
+        ",
+        "
+        // general registers
+        l32i    a2,  sp, +XT_STK_A2
+        l32i    a3,  sp, +XT_STK_A3
+        l32i    a4,  sp, +XT_STK_A4
+        l32i    a5,  sp, +XT_STK_A5
+        l32i    a6,  sp, +XT_STK_A6
+        l32i    a7,  sp, +XT_STK_A7
+        l32i    a8,  sp, +XT_STK_A8
+        l32i    a9,  sp, +XT_STK_A9
+        l32i    a10, sp, +XT_STK_A10
+        l32i    a11, sp, +XT_STK_A11
+        l32i    a12, sp, +XT_STK_A12
+        l32i    a13, sp, +XT_STK_A13
+        l32i    a14, sp, +XT_STK_A14
+        l32i    a15, sp, +XT_STK_A15
+        ret
+        ",
+        "END_FUNCTION restore_fast_context",
+    }
+);
+
+global_asm!(
+    r#"
+    // Handle a level 2-7 interrupt with a `#[interrupt(level, fast)]` handler.
+    // Only the registers saved by save_fast_context are preserved.
+    .macro HANDLE_FAST_INTERRUPT_LEVEL level
+    mov     a0, a1                     // save a1/sp
+    addi    sp, sp, -XT_STK_FAST_FRMSZ
+    .cfi_def_cfa 1, XT_STK_FAST_FRMSZ
+
+    s32i    a0, sp, +XT_STK_A1         // save interruptee's A1/SP
+    CFI_SAVED 1, XT_STK_A1, XT_STK_FAST_FRMSZ
+    s32e    a0, sp, -12                // for debug backtrace
+
+    rsr     a0, EPS\level
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+    CFI_SAVED 0x2e6, XT_STK_PS, XT_STK_FAST_FRMSZ
+
+    rsr     a0, EPC\level
+    s32i    a0, sp, +XT_STK_PC         // save interruptee's PC
+    CFI_SAVED 0x20, XT_STK_PC, XT_STK_FAST_FRMSZ
+    s32e    a0, sp, -16                // for debug backtrace
+
+    rsr     a0, EXCSAVE\level
+    s32i    a0, sp, +XT_STK_A0         // save interruptee's A0
+    CFI_SAVED 0, XT_STK_A0, XT_STK_FAST_FRMSZ
+
+    call0   save_fast_context
+
+    movi    a0, (\level | PS_WOE)
+    wsr     a0, PS
+    rsync
+
+    movi    a6, \level                           // put interrupt level in a6 = a2 in callee
+    movi    a4, __fast_level_\level\()_interrupt
+    callx4  a4                                  // call handler <= actual call!
+
+    call0   restore_fast_context
+
+    l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS
+    wsr     a0, EPS\level
+    l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC
+    wsr     a0, EPC\level
+
+    l32i    a0, sp, +XT_STK_A0        // retrieve interruptee's A0
+    l32i    sp, sp, +XT_STK_A1        // remove exception frame
+    .cfi_def_cfa 1, 0
+    .cfi_restore 0
+    .cfi_restore 1
+    .cfi_undefined 0x20              // PC is back in EPCn
+    rsync                             // ensure PS and EPC written
+
+    rfi     \level
+
+    .endm
+    "#
+);
 
 global_asm!(
     r#"
     .macro HANDLE_INTERRUPT_LEVEL level
+    movi    a0, __fast_level_\level\()_interrupt   // provided as 0 unless a fast handler exists
+    beqz    a0, 1f
+    .cfi_remember_state
+    HANDLE_FAST_INTERRUPT_LEVEL \level
+    .cfi_restore_state
+    1:
+
     SAVE_CONTEXT \level
 
     movi    a0, (\level | PS_WOE)
     wsr     a0, PS
     rsync
 
+    STATS_ENTER
+    SEMIHOSTING_DISPATCH \level, 3f
+    GDBSTUB_DISPATCH \level, 3f
+    STORM_CHECK \level, 3f
+    PROFILING_DISPATCH \level, 3f
+
     movi    a6, \level                     // put interrupt level in a6 = a2 in callee
     mov     a7, sp                         // put address of save frame in a7=a3 in callee
     call4   __level_\level\()_interrupt    // call handler <= actual call!
 
+    3:
+    STATS_EXIT_INTERRUPT \level
     RESTORE_CONTEXT \level
     rfi \level
 
@@ -588,68 +1337,62 @@ global_asm!(
 "#
 );
 
-/// Handle Level 2 Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE2
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_2_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 2", options(noreturn));
-}
-
-/// Handle Level 3 Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE3
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_3_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 3", options(noreturn));
-}
-
-/// Handle Level 4 Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE4
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_4_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 4", options(noreturn));
-}
-
-/// Handle Level 5 Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE5
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_5_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 5", options(noreturn));
-}
-
-/// Handle Level 6 (=Debug) Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE6
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_6_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 6", options(noreturn));
-}
-
-/// Handle Level 7 (=NMI) Interrupt by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE7
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_level_7_interrupt() {
-    asm!("HANDLE_INTERRUPT_LEVEL 7", options(noreturn));
-}
+// Handle Level 2 Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE2
+global_asm!(
+    "HANDLER __default_naked_level_2_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 2",
+    "END_FUNCTION __default_naked_level_2_interrupt"
+);
+
+// Handle Level 3 Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE3
+global_asm!(
+    "HANDLER __default_naked_level_3_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 3",
+    "END_FUNCTION __default_naked_level_3_interrupt"
+);
+
+// Handle Level 4 Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE4
+global_asm!(
+    "HANDLER __default_naked_level_4_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 4",
+    "END_FUNCTION __default_naked_level_4_interrupt"
+);
+
+// Handle Level 5 Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE5
+global_asm!(
+    "HANDLER __default_naked_level_5_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 5",
+    "END_FUNCTION __default_naked_level_5_interrupt"
+);
+
+// Handle Level 6 (=Debug) Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE6
+global_asm!(
+    "HANDLER __default_naked_level_6_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 6",
+    "END_FUNCTION __default_naked_level_6_interrupt"
+);
+
+// Handle Level 7 (=NMI) Interrupt by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE7
+global_asm!(
+    "HANDLER __default_naked_level_7_interrupt",
+    "HANDLE_INTERRUPT_LEVEL 7",
+    "END_FUNCTION __default_naked_level_7_interrupt"
+);
@@ -1,33 +1,11 @@
-use core::arch::{asm, global_asm};
+use core::arch::global_asm;
+
+// The offsets of the exception frame (`XT_STK_*`) are generated from the same layout as `Context`
+include!(concat!(env!("OUT_DIR"), "/exception_frame.rs"));
 
 global_asm!(
     "
-    .set XT_STK_PC,              0
-    .set XT_STK_PS,              4
-    .set XT_STK_A0,              8
-    .equ XT_STK_A1,             12
-    .set XT_STK_A2,             16
-    .set XT_STK_A3,             20
-    .set XT_STK_A4,             24
-    .set XT_STK_A5,             28
-    .set XT_STK_A6,             32
-    .set XT_STK_A7,             36
-    .set XT_STK_A8,             40
-    .set XT_STK_A9,             44
-    .set XT_STK_A10,            48
-    .set XT_STK_A11,            52
-    .set XT_STK_A12,            56
-    .set XT_STK_A13,            60
-    .set XT_STK_A14,            64
-    .set XT_STK_A15,            68
-    .set XT_STK_SAR,            72
-    .set XT_STK_EXCCAUSE,       76
-    .set XT_STK_EXCVADDR,       80
-
-    .set XT_STK_BASESAVE,      240
-    .set XT_STK_FRMSZ,         256  // needs to be multiple of 16 and at least 16 free
-                                    // (for base save region)
-                                    // multiple of 256 allows use of addmi instruction
+    .set XT_STK_BASESAVE,      XT_STK_FRMSZ - 16 // base save region
 
     .set PS_INTLEVEL_EXCM, 3
     .set PS_INTLEVEL_MASK, 0x0000000f
@@ -37,51 +15,92 @@ global_asm!(
     "
 );
 
-/// Save processor state to stack.
-///
-/// *Must only be called with call0.*
-///
-/// Saves all registers except PC, PS, A0, A1
-///
-/// Inputs:
-///     A0 is the return address
-///     A1 is the stack pointers
-///     Exceptions are disabled (PS.EXCM = 1)
-///
-/// Output:
-///     A0 is the return address
-///     A1 is the stack pointer
-///     A3, A9 are used as scratch registers
-///     EPC1 is changed
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn save_context() {
-    asm!(
-        "
-        s32i    a2,  sp, +XT_STK_A2
-        s32i    a3,  sp, +XT_STK_A3
-        s32i    a4,  sp, +XT_STK_A4
-        s32i    a5,  sp, +XT_STK_A5
-        s32i    a6,  sp, +XT_STK_A6
-        s32i    a7,  sp, +XT_STK_A7
-        s32i    a8,  sp, +XT_STK_A8
-        s32i    a9,  sp, +XT_STK_A9
-        s32i    a10, sp, +XT_STK_A10
-        s32i    a11, sp, +XT_STK_A11
-        s32i    a12, sp, +XT_STK_A12
-        s32i    a13, sp, +XT_STK_A13
-        s32i    a14, sp, +XT_STK_A14
-        s32i    a15, sp, +XT_STK_A15
-
-        rsr     a3,  SAR
-        s32i    a3,  sp, +XT_STK_SAR
-
-        ret
-    ",
-        options(noreturn)
-    )
-}
+// The handlers below are defined in global assembly rather than as naked functions, so they can
+// carry their own call frame information (`.cfi_*`). This lets a debugger unwind from a handler
+// into the interruptee through the frame the handler pushed.
+//
+// The DWARF register numbers of A0-A15 are 0-15. PC and PS have no numbers of their own, they use
+// the ones GDB assigns to them on Xtensa (0x20 and 0x2e6). The interruptee's PC is the return
+// address column of the handlers.
+global_asm!(
+    r#"
+    // Start the function `name` in .rwtext
+    .macro FUNCTION name:req
+    .pushsection .rwtext,"ax",@progbits
+    .global \name
+    .type   \name,@function
+    .align  4
+    \name:
+    .cfi_startproc
+    .cfi_def_cfa 1, 0
+    .endm
+
+    .macro END_FUNCTION name:req
+    .cfi_endproc
+    .size   \name, . - \name
+    .popsection
+    .endm
+
+    // Start the function `name` that is entered from an exception vector. Until the
+    // interruptee's PC is saved to the frame it can't be unwound.
+    .macro HANDLER name:req
+    FUNCTION \name
+    .cfi_signal_frame
+    .cfi_return_column 0x20
+    .cfi_undefined 0x20                // PC is in EPCn
+    .cfi_undefined 0                   // A0 is in EXCSAVEn
+    .endm
+
+    // The interruptee's `reg` is saved to the slot `offset` of the frame at A1
+    .macro CFI_SAVED reg:req, offset:req
+    .cfi_offset \reg, \offset - XT_STK_FRMSZ
+    .endm
+    "#
+);
+
+// Save processor state to stack.
+//
+// *Must only be called with call0.*
+//
+// Saves all registers except PC, PS, A0, A1
+//
+// Inputs:
+//     A0 is the return address
+//     A1 is the stack pointers
+//     Exceptions are disabled (PS.EXCM = 1)
+//
+// Output:
+//     A0 is the return address
+//     A1 is the stack pointer
+//     A3, A9 are used as scratch registers
+//     EPC1 is changed
+global_asm!(
+    "
+    FUNCTION save_context
+
+    s32i    a2,  sp, +XT_STK_A2
+    s32i    a3,  sp, +XT_STK_A3
+    s32i    a4,  sp, +XT_STK_A4
+    s32i    a5,  sp, +XT_STK_A5
+    s32i    a6,  sp, +XT_STK_A6
+    s32i    a7,  sp, +XT_STK_A7
+    s32i    a8,  sp, +XT_STK_A8
+    s32i    a9,  sp, +XT_STK_A9
+    s32i    a10, sp, +XT_STK_A10
+    s32i    a11, sp, +XT_STK_A11
+    s32i    a12, sp, +XT_STK_A12
+    s32i    a13, sp, +XT_STK_A13
+    s32i    a14, sp, +XT_STK_A14
+    s32i    a15, sp, +XT_STK_A15
+
+    rsr     a3,  SAR
+    s32i    a3,  sp, +XT_STK_SAR
+
+    ret
+
+    END_FUNCTION save_context
+    "
+);
 
 global_asm!(
     r#"
@@ -89,7 +108,9 @@ global_asm!(
 
     mov     a0, a1                     // save a1/sp
     addmi   sp, sp, -XT_STK_FRMSZ      // bumb stack pointer
+    .cfi_def_cfa 1, XT_STK_FRMSZ
     s32i    a0, sp, +XT_STK_A1         // save interruptee's A1/SP
+    CFI_SAVED 1, XT_STK_A1
 
     .ifc \level,double
     rsr     a0, DEPC
@@ -97,6 +118,7 @@ global_asm!(
     rsr     a0, EPC\level
     .endif
     s32i    a0, sp, +XT_STK_PC         // save interruptee's PC
+    CFI_SAVED 0x20, XT_STK_PC
 
     .ifc \level,double
     rsr     a0, EXCSAVE2               // ok to reuse EXCSAVE7 for double exception as long as
@@ -106,10 +128,12 @@ global_asm!(
     rsr     a0, EXCSAVE\level
     .endif
     s32i    a0, sp, +XT_STK_A0         // save interruptee's A0
+    CFI_SAVED 0, XT_STK_A0
 
     .ifc \level,1
     rsr     a0, PS
     s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+    CFI_SAVED 0x2e6, XT_STK_PS
 
     rsr     a0, EXCCAUSE
     s32i    a0, sp, +XT_STK_EXCCAUSE
@@ -122,6 +146,12 @@ global_asm!(
     s32i    a0, sp, +XT_STK_EXCCAUSE
     rsr     a0, EXCVADDR
     s32i    a0, sp, +XT_STK_EXCVADDR
+    .else
+    .ifnc \level,1
+    rsr     a0, EPS\level
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+    CFI_SAVED 0x2e6, XT_STK_PS
+    .endif
     .endif
 
     call0   save_context
@@ -130,36 +160,34 @@ global_asm!(
     "#
 );
 
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn restore_context() {
-    asm!(
-        "
-        l32i    a3,  sp, +XT_STK_SAR
-        wsr     a3,  SAR
-
-        // general registers
-        l32i    a2,  sp, +XT_STK_A2
-        l32i    a3,  sp, +XT_STK_A3
-        l32i    a4,  sp, +XT_STK_A4
-        l32i    a5,  sp, +XT_STK_A5
-        l32i    a6,  sp, +XT_STK_A6
-        l32i    a7,  sp, +XT_STK_A7
-        l32i    a8,  sp, +XT_STK_A8
-        l32i    a9,  sp, +XT_STK_A9
-        l32i    a10, sp, +XT_STK_A10
-        l32i    a11, sp, +XT_STK_A11
-        l32i    a12, sp, +XT_STK_A12
-        l32i    a13, sp, +XT_STK_A13
-        l32i    a14, sp, +XT_STK_A14
-        l32i    a15, sp, +XT_STK_A15
-
-        ret
-    ",
-        options(noreturn)
-    )
-}
+global_asm!(
+    "
+    FUNCTION restore_context
+
+    l32i    a3,  sp, +XT_STK_SAR
+    wsr     a3,  SAR
+
+    // general registers
+    l32i    a2,  sp, +XT_STK_A2
+    l32i    a3,  sp, +XT_STK_A3
+    l32i    a4,  sp, +XT_STK_A4
+    l32i    a5,  sp, +XT_STK_A5
+    l32i    a6,  sp, +XT_STK_A6
+    l32i    a7,  sp, +XT_STK_A7
+    l32i    a8,  sp, +XT_STK_A8
+    l32i    a9,  sp, +XT_STK_A9
+    l32i    a10, sp, +XT_STK_A10
+    l32i    a11, sp, +XT_STK_A11
+    l32i    a12, sp, +XT_STK_A12
+    l32i    a13, sp, +XT_STK_A13
+    l32i    a14, sp, +XT_STK_A14
+    l32i    a15, sp, +XT_STK_A15
+
+    ret
+
+    END_FUNCTION restore_context
+    "
+);
 
 global_asm!(
     r#"
@@ -168,178 +196,149 @@ global_asm!(
     // Restore context and return
     call0   restore_context
 
-    .ifc \level,1
+    .ifnc \level,double
     l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS
+    .ifc \level,1
     wsr     a0, PS
+    .else
+    wsr     a0, EPS\level
+    .endif
     l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC
     wsr     a0, EPC\level
     .endif
 
     l32i    a0, sp, +XT_STK_A0        // retrieve interruptee's A0
     l32i    sp, sp, +XT_STK_A1        // remove exception frame
+    .cfi_def_cfa 1, 0
+    .cfi_restore 0
+    .cfi_restore 1
+    .cfi_undefined 0x20               // PC is back in EPCn
     rsync                             // ensure PS and EPC written
 
     .endm
     "#
 );
 
-/// Handle Other Exceptions or Level 1 interrupt by storing full context and then
-/// calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
-
-        rsr.EXCCAUSE a2                   // put cause in a2
-        beqi    a2, 4, .Level1Interrupt   // cause 4 is interrupt
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __exception               // call handler <= actual call!
-
-        j .RestoreContext
-
-        .Level1Interrupt:
-        movi    a2, 1                     // put interrupt level in a2 in callee
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __level_1_interrupt       // call handler <= actual call!
-
-        .RestoreContext:
-        RESTORE_CONTEXT 1
-
-        .byte 0x00, 0x30, 0x00            // rfe
-                                          // TODO: 20200509, not supported in llvm yet
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle Double Exceptions by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in ???
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_double_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT double
-
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __double_exception        // call handler <= actual call!
-
-        RESTORE_CONTEXT double
-
-        .byte 0x00, 0x30, 0x00            // rfe
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle Kernel Exceptions by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_kernel_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
-
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __kernel_exception               // call handler <= actual call!
-
-        RESTORE_CONTEXT 1
-
-        .byte 0x00, 0x30, 0x00            // rfe   // PS.EXCM is cleared
-                                          // TODO: 20200509, not supported in llvm yet
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle NMI Exceptions by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_nmi_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
-
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __nmi_exception               // call handler <= actual call!
-
-        RESTORE_CONTEXT 1
-
-        .byte 0x00, 0x30, 0x00            // rfe
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle Debug Exceptions by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_debug_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
-
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __debug_exception               // call handler <= actual call!
-
-        RESTORE_CONTEXT 1
-
-        .byte 0x00, 0x30, 0x00            // rfe
-        ",
-        options(noreturn)
-    )
-}
-
-/// Handle Alloc Exceptions by storing full context and then calling regular function
-///
-/// # Input:
-///    * A0 stored in EXCSAVE1
-#[naked]
-#[no_mangle]
-#[link_section = ".rwtext"]
-unsafe extern "C" fn __default_naked_alloc_exception() {
-    asm!(
-        "
-        SAVE_CONTEXT 1
-
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __alloc_exception         // call handler <= actual call!
-
-        RESTORE_CONTEXT 1
-
-        .byte 0x00, 0x30, 0x00            // rfe
-        ",
-        options(noreturn)
-    )
-}
+// Handle Other Exceptions or Level 1 interrupt by storing full context and then
+// calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE1
+global_asm!(
+    "
+    HANDLER __default_naked_exception
+
+    SAVE_CONTEXT 1
+
+    rsr.EXCCAUSE a2                   // put cause in a2
+    beqi    a2, 4, .Level1Interrupt   // cause 4 is interrupt
+
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __exception               // call handler <= actual call!
+
+    j .RestoreContext
+
+    .Level1Interrupt:
+    movi    a2, 1                     // put interrupt level in a2 in callee
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __level_1_interrupt       // call handler <= actual call!
+
+    .RestoreContext:
+    RESTORE_CONTEXT 1
+
+    .byte 0x00, 0x30, 0x00            // rfe
+                                      // TODO: 20200509, not supported in llvm yet
+
+    END_FUNCTION __default_naked_exception
+    "
+);
+
+// Handle Double Exceptions by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in ???
+global_asm!(
+    "
+    HANDLER __default_naked_double_exception
+
+    SAVE_CONTEXT double
+
+    l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __double_exception        // call handler <= actual call!
+
+    RESTORE_CONTEXT double
+
+    .byte 0x00, 0x30, 0x00            // rfe
+
+    END_FUNCTION __default_naked_double_exception
+    "
+);
+
+// Handle the NMI (level 3) by storing full context and then calling the level 3 interrupt handler
+//
+// # Input:
+//    * A0 stored in EXCSAVE3
+global_asm!(
+    "
+    HANDLER __default_naked_nmi_exception
+
+    SAVE_CONTEXT 3
+
+    movi    a2, 3                     // put interrupt level in a2
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __level_3_interrupt       // call handler <= actual call!
+
+    RESTORE_CONTEXT 3
+
+    .byte 0x10, 0x33, 0x00            // rfi 3
+
+    END_FUNCTION __default_naked_nmi_exception
+    "
+);
+
+// Handle Debug Exceptions (level 2) by storing full context and then calling the level 2
+// interrupt handler
+//
+// # Input:
+//    * A0 stored in EXCSAVE2
+global_asm!(
+    "
+    HANDLER __default_naked_debug_exception
+
+    SAVE_CONTEXT 2
+
+    movi    a2, 2                     // put interrupt level in a2
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __level_2_interrupt       // call handler <= actual call!
+
+    RESTORE_CONTEXT 2
+
+    .byte 0x10, 0x32, 0x00            // rfi 2
+
+    END_FUNCTION __default_naked_debug_exception
+    "
+);
+
+// Handle Alloc Exceptions by storing full context and then calling regular function
+//
+// # Input:
+//    * A0 stored in EXCSAVE1
+global_asm!(
+    "
+    HANDLER __default_naked_alloc_exception
+
+    SAVE_CONTEXT 1
+
+    l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
+
+    mov     a3, sp                    // put address of save frame in a3
+    call0   __alloc_exception         // call handler <= actual call!
+
+    RESTORE_CONTEXT 1
+
+    .byte 0x00, 0x30, 0x00            // rfe
+
+    END_FUNCTION __default_naked_alloc_exception
+    "
+);
@@ -0,0 +1,242 @@
+//! Lazy coprocessor context saving
+//!
+//! With the `lazy-fpu` feature, exception and interrupt handlers start with the FPU (coprocessor
+//! 0) disabled instead of saving its registers on every entry. The first floating point
+//! instruction of a handler raises `Cp0Disabled`: the live FPU registers are then stored into the
+//! frame of the interrupted code and the FPU is enabled for the handler. `restore_context`
+//! reloads them when that frame is removed.
+//!
+//! To find that frame, `save_context` links every frame to the previous innermost frame of its
+//! core (`XT_STK_CPFRAME`) and `restore_context` unlinks it again.
+//!
+//! The `pie` feature does the same for the ESP32-S3 PIE vector unit (coprocessor 1) through
+//! `Cp1Disabled`. Its state (Q0-Q7, ACCX, QACC, SAR_BYTE, FFT_BIT_WIDTH and UA_STATE) is too
+//! large for the frame, so it goes into a per core pool of save areas instead. Frames are removed
+//! in the reverse order they got an area assigned, so `restore_context` simply takes back the
+//! last allocated area. There are 8 areas per core: a 9th nested handler using the PIE while all
+//! interrupted ones still have their registers saved is a fatal error.
+
+use core::arch::asm;
+
+use super::Context;
+
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+include!(concat!(env!("OUT_DIR"), "/coprocessor_layout.rs"));
+
+/// Set in `Context::CPENABLE` when the FPU registers are saved into that frame
+#[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+pub(super) const CP0_SAVED: u32 = 1 << 8;
+
+/// Set in `Context::CPENABLE` when the PIE registers are saved for that frame
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+const CP1_SAVED: u32 = 1 << 9;
+
+/// Maximum number of frames per core that can have their PIE registers saved at the same time
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+const CP1_SAVE_AREAS: usize = 8;
+
+// `save_pie` and `restore_context` store Q0-Q7 with post-incrementing stores from the offset of
+// Q0, the offsets of the other registers come from the layout generated from `XCHAL_CP1_SA_LIST`
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+const _: () = assert!(
+    CP1_SA_Q1 == CP1_SA_Q0 + 16
+        && CP1_SA_Q2 == CP1_SA_Q1 + 16
+        && CP1_SA_Q3 == CP1_SA_Q2 + 16
+        && CP1_SA_Q4 == CP1_SA_Q3 + 16
+        && CP1_SA_Q5 == CP1_SA_Q4 + 16
+        && CP1_SA_Q6 == CP1_SA_Q5 + 16
+        && CP1_SA_Q7 == CP1_SA_Q6 + 16
+        && CP1_SA_ALIGN <= 16
+);
+
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+#[repr(C, align(16))]
+struct Cp1SaveArea([u8; CP1_SA_SIZE]);
+
+/// Innermost exception frame of each core, null outside of handlers
+#[no_mangle]
+static mut __xtensa_lx_rt_cp_frames: [*mut Context; 2] = [core::ptr::null_mut(); 2];
+
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+static mut CP1_SAVE_POOL: [[Cp1SaveArea; CP1_SAVE_AREAS]; 2] = {
+    const AREA: Cp1SaveArea = Cp1SaveArea([0; CP1_SA_SIZE]);
+    const AREAS: [Cp1SaveArea; CP1_SAVE_AREAS] = [AREA; CP1_SAVE_AREAS];
+    [AREAS; 2]
+};
+
+/// Next free PIE save area of each core, null until the first one is needed
+///
+/// `restore_context` steps this back by `XT_CP1_SA_SIZE` when it reloads the PIE registers.
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+#[no_mangle]
+static mut __xtensa_lx_rt_cp1_next: [*mut Cp1SaveArea; 2] = [core::ptr::null_mut(); 2];
+
+/// Handle `ExceptionCause::Cp0Disabled`
+///
+/// `save_frame` is the frame of the `Cp0Disabled` exception itself, it links to the frame of the
+/// handler that executed the floating point instruction. The FPU registers currently belong to
+/// the code that handler interrupted, so they are saved there.
+#[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+#[link_section = ".rwtext"]
+pub(super) unsafe fn cp0_disabled(save_frame: &mut Context) {
+    enable_cp(0);
+
+    if let Some(owner) = (save_frame.CPFRAME as *mut Context).as_mut() {
+        if owner.CPENABLE & CP0_SAVED == 0 {
+            save_fpu(owner);
+            owner.CPENABLE |= CP0_SAVED;
+        }
+    }
+
+    // return with the FPU enabled, so the instruction can be executed again
+    save_frame.CPENABLE |= 1;
+}
+
+/// Handle `ExceptionCause::Cp1Disabled`
+///
+/// Like [`cp0_disabled`], but the PIE registers are stored into the next free save area of this
+/// core. Running out of save areas is a [`Fatal::CoprocessorSaveAreas`] error.
+///
+/// [`Fatal::CoprocessorSaveAreas`]: crate::fatal::Fatal::CoprocessorSaveAreas
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+#[link_section = ".rwtext"]
+pub(super) unsafe fn cp1_disabled(save_frame: &mut Context) {
+    enable_cp(1);
+
+    if let Some(owner) = (save_frame.CPFRAME as *mut Context).as_mut() {
+        if owner.CPENABLE & CP1_SAVED == 0 {
+            let core = crate::core_id();
+            let pool = &mut CP1_SAVE_POOL[core];
+            let next = &mut __xtensa_lx_rt_cp1_next[core];
+            if next.is_null() {
+                *next = pool.as_mut_ptr();
+            }
+            if *next == pool.as_mut_ptr().add(CP1_SAVE_AREAS) {
+                crate::fatal::fatal(crate::fatal::Fatal::CoprocessorSaveAreas(1, save_frame));
+            }
+
+            save_pie(*next);
+            *next = next.add(1);
+            owner.CPENABLE |= CP1_SAVED;
+        }
+    }
+
+    // return with the PIE enabled, so the instruction can be executed again
+    save_frame.CPENABLE |= 1 << 1;
+}
+
+#[inline(always)]
+unsafe fn enable_cp(cp: u32) {
+    let cpenable: u32;
+    asm!("rsr.cpenable {0}", out(reg) cpenable, options(nostack));
+    asm!("wsr.cpenable {0}", "rsync", in(reg) cpenable | (1 << cp), options(nostack));
+}
+
+#[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+#[inline(always)]
+unsafe fn save_pie(area: *mut Cp1SaveArea) {
+    asm!(
+        "
+        rur     {1}, accx_0
+        s32i    {1}, {0}, {accx_0}
+        rur     {1}, accx_1
+        s32i    {1}, {0}, {accx_1}
+        rur     {1}, qacc_h_0
+        s32i    {1}, {0}, {qacc_h_0}
+        rur     {1}, qacc_h_1
+        s32i    {1}, {0}, {qacc_h_1}
+        rur     {1}, qacc_h_2
+        s32i    {1}, {0}, {qacc_h_2}
+        rur     {1}, qacc_h_3
+        s32i    {1}, {0}, {qacc_h_3}
+        rur     {1}, qacc_h_4
+        s32i    {1}, {0}, {qacc_h_4}
+        rur     {1}, qacc_l_0
+        s32i    {1}, {0}, {qacc_l_0}
+        rur     {1}, qacc_l_1
+        s32i    {1}, {0}, {qacc_l_1}
+        rur     {1}, qacc_l_2
+        s32i    {1}, {0}, {qacc_l_2}
+        rur     {1}, qacc_l_3
+        s32i    {1}, {0}, {qacc_l_3}
+        rur     {1}, qacc_l_4
+        s32i    {1}, {0}, {qacc_l_4}
+        rur     {1}, sar_byte
+        s32i    {1}, {0}, {sar_byte}
+        rur     {1}, fft_bit_width
+        s32i    {1}, {0}, {fft_bit_width}
+        rur     {1}, ua_state_0
+        s32i    {1}, {0}, {ua_state_0}
+        rur     {1}, ua_state_1
+        s32i    {1}, {0}, {ua_state_1}
+        rur     {1}, ua_state_2
+        s32i    {1}, {0}, {ua_state_2}
+        rur     {1}, ua_state_3
+        s32i    {1}, {0}, {ua_state_3}
+        addi    {0}, {0}, {q0}
+        ee.vst.128.ip q0, {0}, 16
+        ee.vst.128.ip q1, {0}, 16
+        ee.vst.128.ip q2, {0}, 16
+        ee.vst.128.ip q3, {0}, 16
+        ee.vst.128.ip q4, {0}, 16
+        ee.vst.128.ip q5, {0}, 16
+        ee.vst.128.ip q6, {0}, 16
+        ee.vst.128.ip q7, {0}, 16
+        ",
+        inout(reg) area => _,
+        out(reg) _,
+        accx_0 = const CP1_SA_ACCX_0,
+        accx_1 = const CP1_SA_ACCX_1,
+        qacc_h_0 = const CP1_SA_QACC_H_0,
+        qacc_h_1 = const CP1_SA_QACC_H_1,
+        qacc_h_2 = const CP1_SA_QACC_H_2,
+        qacc_h_3 = const CP1_SA_QACC_H_3,
+        qacc_h_4 = const CP1_SA_QACC_H_4,
+        qacc_l_0 = const CP1_SA_QACC_L_0,
+        qacc_l_1 = const CP1_SA_QACC_L_1,
+        qacc_l_2 = const CP1_SA_QACC_L_2,
+        qacc_l_3 = const CP1_SA_QACC_L_3,
+        qacc_l_4 = const CP1_SA_QACC_L_4,
+        sar_byte = const CP1_SA_SAR_BYTE,
+        fft_bit_width = const CP1_SA_FFT_BIT_WIDTH,
+        ua_state_0 = const CP1_SA_UA_STATE_0,
+        ua_state_1 = const CP1_SA_UA_STATE_1,
+        ua_state_2 = const CP1_SA_UA_STATE_2,
+        ua_state_3 = const CP1_SA_UA_STATE_3,
+        q0 = const CP1_SA_Q0,
+        options(nostack)
+    );
+}
+
+#[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+#[inline(always)]
+unsafe fn save_fpu(frame: &mut Context) {
+    let fcr: u32;
+    let fsr: u32;
+    asm!("rur {0}, fcr", "rur {1}, fsr", out(reg) fcr, out(reg) fsr, options(nostack));
+    frame.FCR = fcr;
+    frame.FSR = fsr;
+
+    asm!(
+        "
+        ssi     f0,  {0}, 0
+        ssi     f1,  {0}, 4
+        ssi     f2,  {0}, 8
+        ssi     f3,  {0}, 12
+        ssi     f4,  {0}, 16
+        ssi     f5,  {0}, 20
+        ssi     f6,  {0}, 24
+        ssi     f7,  {0}, 28
+        ssi     f8,  {0}, 32
+        ssi     f9,  {0}, 36
+        ssi     f10, {0}, 40
+        ssi     f11, {0}, 44
+        ssi     f12, {0}, 48
+        ssi     f13, {0}, 52
+        ssi     f14, {0}, 56
+        ssi     f15, {0}, 60
+        ",
+        in(reg) core::ptr::addr_of_mut!(frame.F0),
+        options(nostack)
+    );
+}
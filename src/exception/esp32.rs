@@ -1,69 +1,35 @@
 use core::arch::asm;
 
-use super::ExceptionCause;
-
-/// State of the CPU saved when entering exception or interrupt
-///
-/// Must be aligned with assembly frame format in assembly_esp32
-#[repr(C)]
-#[allow(non_snake_case)]
-#[derive(Debug, Clone, Copy)]
-pub struct Context {
-    pub PC: u32,
-    pub PS: u32,
-
-    pub A0: u32,
-    pub A1: u32,
-    pub A2: u32,
-    pub A3: u32,
-    pub A4: u32,
-    pub A5: u32,
-    pub A6: u32,
-    pub A7: u32,
-    pub A8: u32,
-    pub A9: u32,
-    pub A10: u32,
-    pub A11: u32,
-    pub A12: u32,
-    pub A13: u32,
-    pub A14: u32,
-    pub A15: u32,
-    pub SAR: u32,
-    pub EXCCAUSE: u32,
-    pub EXCVADDR: u32,
-    pub LBEG: u32,
-    pub LEND: u32,
-    pub LCOUNT: u32,
-    pub THREADPTR: u32,
-    pub SCOMPARE1: u32,
-    pub BR: u32,
-    pub ACCLO: u32,
-    pub ACCHI: u32,
-    pub M0: u32,
-    pub M1: u32,
-    pub M2: u32,
-    pub M3: u32,
-    pub F64R_LO: u32,
-    pub F64R_HI: u32,
-    pub F64S: u32,
-    pub FCR: u32,
-    pub FSR: u32,
-    pub F0: u32,
-    pub F1: u32,
-    pub F2: u32,
-    pub F3: u32,
-    pub F4: u32,
-    pub F5: u32,
-    pub F6: u32,
-    pub F7: u32,
-    pub F8: u32,
-    pub F9: u32,
-    pub F10: u32,
-    pub F11: u32,
-    pub F12: u32,
-    pub F13: u32,
-    pub F14: u32,
-    pub F15: u32,
+use super::{ExceptionCause, ExceptionVector};
+use crate::registers::Ps;
+
+include!(concat!(env!("OUT_DIR"), "/context.rs"));
+
+impl Context {
+    /// The vector an exception was taken through, according to the interruptee's PS.UM
+    ///
+    /// Only meaningful for exceptions and level 1 interrupts.
+    pub fn vector(&self) -> ExceptionVector {
+        if Ps(self.PS).um() {
+            ExceptionVector::User
+        } else {
+            ExceptionVector::Kernel
+        }
+    }
+
+    /// Whether the FPU registers were saved to the frame
+    ///
+    /// With `lazy-fpu` they are only saved after the FPU was used.
+    #[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+    pub(crate) fn fpu_saved(&self) -> bool {
+        self.CPENABLE & super::coprocessor::CP0_SAVED != 0
+    }
+
+    /// Whether the FPU registers were saved to the frame
+    #[cfg(all(XCHAL_HAVE_FP, not(feature = "lazy-fpu")))]
+    pub(crate) fn fpu_saved(&self) -> bool {
+        true
+    }
 }
 
 extern "Rust" {
@@ -71,7 +37,9 @@ extern "Rust" {
     fn __exception(cause: ExceptionCause, save_frame: &mut Context);
     /// This symbol will be provided by the user via `#[exception]`
     fn __user_exception(cause: ExceptionCause, save_frame: &mut Context);
-    /// No attribute is supplied for this symbol as the double exception can hardly occur
+    /// This symbol will be provided by the user via `#[exception(kernel)]`
+    fn __kernel_exception(cause: ExceptionCause, save_frame: &mut Context);
+    /// This symbol will be provided by the user via `#[double_exception]`
     fn __double_exception(cause: ExceptionCause, save_frame: &mut Context);
 
     /// This symbol will be provided by the user via `#[interrupt(1)]`
@@ -93,25 +61,41 @@ extern "Rust" {
 #[no_mangle]
 #[link_section = ".rwtext"]
 unsafe extern "C" fn __default_exception(cause: ExceptionCause, save_frame: &mut Context) {
-    __user_exception(cause, save_frame)
+    #[cfg(all(XCHAL_HAVE_FP, feature = "lazy-fpu"))]
+    if let ExceptionCause::Cp0Disabled = cause {
+        return super::coprocessor::cp0_disabled(save_frame);
+    }
+    #[cfg(all(XCHAL_HAVE_CP1, feature = "pie"))]
+    if let ExceptionCause::Cp1Disabled = cause {
+        return super::coprocessor::cp1_disabled(save_frame);
+    }
+    #[cfg(feature = "syscall")]
+    if let ExceptionCause::Syscall = cause {
+        return crate::syscall::dispatch(save_frame);
+    }
+
+    match save_frame.vector() {
+        ExceptionVector::User => __user_exception(cause, save_frame),
+        ExceptionVector::Kernel => __kernel_exception(cause, save_frame),
+    }
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
 extern "C" fn __default_user_exception(cause: ExceptionCause, save_frame: &Context) {
-    panic!("Exception: {:?}, {:08x?}", cause, save_frame)
+    crate::fatal::fatal(crate::fatal::Fatal::Exception(cause, save_frame))
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
 extern "C" fn __default_interrupt(level: u32, save_frame: &Context) {
-    panic!("Interrupt: {:?}, {:08x?}", level, save_frame)
+    crate::interrupt::spurious(level, save_frame)
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
 extern "C" fn __default_double_exception(cause: ExceptionCause, save_frame: &Context) {
-    panic!("Double Exception: {:?}, {:08x?}", cause, save_frame)
+    crate::fatal::fatal(crate::fatal::Fatal::DoubleException(cause, save_frame))
 }
 
 // Raw vector handlers
@@ -1,59 +1,60 @@
 use core::arch::asm;
 
-use super::ExceptionCause;
-
-/// State of the CPU saved when entering exception or interrupt
-///
-/// Must be aligned with assembly frame format in assembly_lx106
-#[repr(C)]
-#[allow(non_snake_case)]
-#[derive(Debug, Default)]
-pub struct Context {
-    PC: u32,
-    PS: u32,
-
-    A0: u32,
-    A1: u32,
-    A2: u32,
-    A3: u32,
-    A4: u32,
-    A5: u32,
-    A6: u32,
-    A7: u32,
-    A8: u32,
-    A9: u32,
-    A10: u32,
-    A11: u32,
-    A12: u32,
-    A13: u32,
-    A14: u32,
-    A15: u32,
-    SAR: u32,
-    EXCCAUSE: u32,
-    EXCVADDR: u32,
+use super::{ExceptionCause, ExceptionVector};
+use crate::registers::Ps;
+
+include!(concat!(env!("OUT_DIR"), "/context.rs"));
+
+impl Context {
+    /// The vector an exception was taken through, according to the interruptee's PS.UM
+    ///
+    /// Only meaningful for exceptions and level 1 interrupts.
+    pub fn vector(&self) -> ExceptionVector {
+        if Ps(self.PS).um() {
+            ExceptionVector::User
+        } else {
+            ExceptionVector::Kernel
+        }
+    }
 }
 
 extern "Rust" {
     /// This symbol will be provided by the user via `#[exception]`
-    fn __exception(cause: ExceptionCause);
-    /// No attribute is supplied for this symbol as the double exception can hardly occur
-    fn __double_exception(cause: ExceptionCause);
+    fn __user_exception(cause: ExceptionCause, save_frame: &mut Context);
+    /// This symbol will be provided by the user via `#[exception(kernel)]`
+    fn __kernel_exception(cause: ExceptionCause, save_frame: &mut Context);
+    /// This symbol will be provided by the user via `#[double_exception]`
+    fn __double_exception(cause: ExceptionCause, save_frame: &mut Context);
 
-    /// This symbol will be provided by the user via `#[interrupt]`
-    fn __level_1_interrupt(level: u32);
+    /// This symbol will be provided by the user via `#[interrupt(1)]`
+    fn __level_1_interrupt(level: u32, save_frame: &mut Context);
+    /// This symbol will be provided by the user via `#[interrupt(2)]`, taken on debug exceptions
+    fn __level_2_interrupt(level: u32, save_frame: &mut Context);
+    /// This symbol will be provided by the user via `#[interrupt(3)]`, taken on NMIs
+    fn __level_3_interrupt(level: u32, save_frame: &mut Context);
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
-extern "C" fn __default_exception(cause: ExceptionCause, save_frame: &Context) {
-    panic!("Exception: {:?}, {:08x?}", cause, save_frame)
+unsafe extern "C" fn __default_exception(cause: ExceptionCause, save_frame: &mut Context) {
+    match save_frame.vector() {
+        ExceptionVector::User => __user_exception(cause, save_frame),
+        ExceptionVector::Kernel => __kernel_exception(cause, save_frame),
+    }
+}
+
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __default_user_exception(cause: ExceptionCause, save_frame: &Context) {
+    crate::fatal::fatal(crate::fatal::Fatal::Exception(cause, save_frame))
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
 extern "C" fn __default_double_exception(cause: ExceptionCause, save_frame: &Context) {
-    panic!("Double Exception: {:?}, {:08x?}", cause, save_frame)
+    crate::fatal::fatal(crate::fatal::Fatal::DoubleException(cause, save_frame))
 }
+
 #[no_mangle]
 #[link_section = ".rwtext"]
 extern "C" fn __default_interrupt(_level: u32, _save_frame: &Context) {}
@@ -64,7 +65,7 @@ extern "C" fn __default_interrupt(_level: u32, _save_frame: &Context) {}
 unsafe extern "C" fn _DebugExceptionVector() {
     asm!(
         "
-        wsr a0, EXCSAVE1 // preserve a0
+        wsr a0, EXCSAVE2 // preserve a0, the debug level is 2
         call0 __naked_debug_exception     // used as long jump
         ",
         options(noreturn)
@@ -77,7 +78,7 @@ unsafe extern "C" fn _DebugExceptionVector() {
 unsafe extern "C" fn _NMIExceptionVector() {
     asm!(
         "
-        wsr a0, EXCSAVE1 // preserve a0
+        wsr a0, EXCSAVE3 // preserve a0, the NMI level is 3
         call0 __naked_nmi_exception     // used as long jump
         ",
         options(noreturn)
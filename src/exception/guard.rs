@@ -0,0 +1,61 @@
+//! Stack pointer and nesting checks of the exception entry
+//!
+//! With the `stack-guard` feature every handler entry checks that the exception frame fits
+//! between the linker symbols `_stack_bounds_start` and `_stack_bounds_end`, by default the
+//! `RWDATA` region. Level 1 interrupts and exceptions also count their nesting per core. When the
+//! stack pointer is out of bounds or more than [`set_depth_limit`] handlers are nested, e.g.
+//! because a handler keeps faulting, the runtime switches to the emergency stack of the core and
+//! calls the fatal error handler.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{Context, ExceptionCause};
+use crate::fatal::{fatal, Fatal};
+
+static DEPTH_LIMIT: AtomicU32 = AtomicU32::new(8);
+
+// Nested entries always leave before the one they interrupted, so the counters need no locking
+static mut DEPTH: [u32; 2] = [0; 2];
+
+/// Maximum number of nested level 1 interrupts and exceptions, 8 by default
+pub fn set_depth_limit(limit: u32) {
+    DEPTH_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Number of level 1 interrupts and exceptions the current core is handling
+pub fn depth() -> u32 {
+    unsafe { DEPTH[crate::core_id()] }
+}
+
+/// Returns whether the depth limit is exceeded
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_depth_enter() -> u32 {
+    unsafe {
+        let depth = &mut DEPTH[crate::core_id()];
+        *depth += 1;
+        (*depth > DEPTH_LIMIT.load(Ordering::Relaxed)) as u32
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_depth_exit() {
+    unsafe { DEPTH[crate::core_id()] -= 1 };
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_depth_exceeded(cause: ExceptionCause, save_frame: &Context) -> ! {
+    fatal(Fatal::NestingLimit(cause, save_frame))
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_stack_fault(level: u32, save_frame: &Context) -> ! {
+    fatal(Fatal::StackOutOfBounds(level, save_frame))
+}
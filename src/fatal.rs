@@ -0,0 +1,203 @@
+//! Fatal error handling
+//!
+//! Exceptions without handler, double exceptions, the stack faults detected with the `stack-guard`
+//! feature, running out of PIE save areas with the `pie` feature and, with
+//! [`SpuriousPolicy::Panic`], interrupts of levels without handler are fatal. The function defined
+//! with `#[fatal_handler]` gets the error and decides how to go on with the returned
+//! [`FatalAction`]:
+//!
+//! ```ignore
+//! #[fatal_handler]
+//! fn fatal(error: &Fatal) -> FatalAction {
+//!     error.dump(|s| uart_write(s.as_bytes()));
+//!     FatalAction::Reset(chip_reset)
+//! }
+//! ```
+//!
+//! Without handler the action is [`FatalAction::Panic`], or [`FatalAction::Halt`] with the
+//! `fatal-halt` feature and [`FatalAction::Spin`] with the `fatal-spin` feature.
+//!
+//! The panic message includes the whole [`Context`] formatted with `{:08x?}`, which pulls in a
+//! good part of `core::fmt`. With the `fatal-compact` feature it is only a static string naming
+//! the kind of error, e.g. `Exception`, so nothing is formatted; a `#[fatal_handler]` can still
+//! write the registers with [`Fatal::dump`], which doesn't use `core::fmt` either. With the
+//! `defmt` feature the error is logged with `defmt::panic!`, taking precedence over
+//! `fatal-compact`.
+//!
+//! [`SpuriousPolicy::Panic`]: crate::interrupt::SpuriousPolicy::Panic
+
+use core::arch::asm;
+
+use crate::exception::{Context, ExceptionCause};
+
+#[cfg(all(feature = "fatal-halt", feature = "fatal-spin"))]
+compile_error!("Only one of the `fatal-halt` and `fatal-spin` features can be enabled");
+
+/// A fatal error
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Fatal<'a> {
+    /// Exception without handler
+    Exception(ExceptionCause, &'a Context),
+    /// Exception while the processor was still setting up the handling of another one
+    DoubleException(ExceptionCause, &'a Context),
+    /// Interrupt of a level without handler
+    Interrupt(u32, &'a Context),
+    /// Stack pointer out of bounds on entry of a handler of the given level (`stack-guard`)
+    StackOutOfBounds(u32, &'a Context),
+    /// Too many nested exceptions and level 1 interrupts (`stack-guard`)
+    NestingLimit(ExceptionCause, &'a Context),
+    /// Too many nested handlers saving the registers of the given coprocessor at the same time
+    /// (`pie`)
+    CoprocessorSaveAreas(u32, &'a Context),
+}
+
+/// What to do after a fatal error
+#[derive(Debug, Clone, Copy)]
+pub enum FatalAction {
+    /// Mask all maskable interrupts and wait for interrupts forever
+    Halt,
+    /// Call the given function, e.g. a chip reset
+    Reset(fn() -> !),
+    /// Busy loop forever
+    Spin,
+    /// Panic with the error
+    Panic,
+}
+
+impl FatalAction {
+    /// The action without `#[fatal_handler]`
+    pub const DEFAULT: FatalAction = if cfg!(feature = "fatal-halt") {
+        FatalAction::Halt
+    } else if cfg!(feature = "fatal-spin") {
+        FatalAction::Spin
+    } else {
+        FatalAction::Panic
+    };
+}
+
+impl Fatal<'_> {
+    /// The saved state of the interrupted code
+    pub fn context(&self) -> &Context {
+        match self {
+            Fatal::Exception(_, context)
+            | Fatal::DoubleException(_, context)
+            | Fatal::Interrupt(_, context)
+            | Fatal::StackOutOfBounds(_, context)
+            | Fatal::NestingLimit(_, context)
+            | Fatal::CoprocessorSaveAreas(_, context) => context,
+        }
+    }
+
+    /// Write a one line register dump, without using `core::fmt`
+    ///
+    /// `Exception 28 PC=400d1234 PS=00060030 ...`, the number is the cause, the level of an
+    /// interrupt or handler entered with an invalid stack, or the coprocessor.
+    pub fn dump(&self, mut out: impl FnMut(&str)) {
+        let (kind, number) = match self {
+            Fatal::Exception(cause, _) => ("Exception", cause_number(cause)),
+            Fatal::DoubleException(cause, _) => ("Double Exception", cause_number(cause)),
+            Fatal::Interrupt(level, _) => ("Interrupt", *level),
+            Fatal::StackOutOfBounds(level, _) => ("Stack Out Of Bounds", *level),
+            Fatal::NestingLimit(cause, _) => ("Nesting Limit", cause_number(cause)),
+            Fatal::CoprocessorSaveAreas(cp, _) => ("Coprocessor Save Areas", *cp),
+        };
+        out(kind);
+        out(" ");
+        let mut digits = [0u8; 10];
+        out(decimal(number, &mut digits));
+
+        for (name, value) in self.context().dump_registers() {
+            let mut hex = [0u8; 8];
+            for (i, digit) in hex.iter_mut().enumerate() {
+                *digit = b"0123456789abcdef"[((value >> (28 - 4 * i)) & 0xf) as usize];
+            }
+            out(" ");
+            out(name);
+            out("=");
+            out(unsafe { core::str::from_utf8_unchecked(&hex) });
+        }
+    }
+}
+
+extern "Rust" {
+    /// This symbol will be provided by the user via `#[fatal_handler]`
+    fn __fatal_handler(error: &Fatal) -> FatalAction;
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[rustfmt::skip]
+pub extern "Rust" fn default_fatal_handler(_error: &Fatal) -> FatalAction { FatalAction::DEFAULT }
+
+/// Handle a fatal error according to the `#[fatal_handler]`
+#[inline(never)]
+pub(crate) fn fatal(error: Fatal) -> ! {
+    #[cfg(all(
+        any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+        feature = "gdbstub"
+    ))]
+    crate::gdbstub::fatal(&error);
+
+    match unsafe { __fatal_handler(&error) } {
+        FatalAction::Halt => loop {
+            unsafe { asm!("waiti 15", options(nostack)) };
+        },
+        FatalAction::Reset(reset) => reset(),
+        FatalAction::Spin => loop {},
+        FatalAction::Panic => panic_with(&error),
+    }
+}
+
+#[cfg(not(any(feature = "fatal-compact", feature = "defmt")))]
+fn panic_with(error: &Fatal) -> ! {
+    match error {
+        Fatal::Exception(cause, frame) => panic!("Exception: {:?}, {:08x?}", cause, frame),
+        Fatal::DoubleException(cause, frame) => {
+            panic!("Double Exception: {:?}, {:08x?}", cause, frame)
+        }
+        Fatal::Interrupt(level, frame) => panic!("Interrupt: {:?}, {:08x?}", level, frame),
+        Fatal::StackOutOfBounds(level, frame) => {
+            panic!("Stack Out Of Bounds: {:?}, {:08x?}", level, frame)
+        }
+        Fatal::NestingLimit(cause, frame) => panic!("Nesting Limit: {:?}, {:08x?}", cause, frame),
+        Fatal::CoprocessorSaveAreas(cp, frame) => {
+            panic!("Coprocessor Save Areas: {:?}, {:08x?}", cp, frame)
+        }
+    }
+}
+
+#[cfg(all(feature = "fatal-compact", not(feature = "defmt")))]
+fn panic_with(error: &Fatal) -> ! {
+    match error {
+        Fatal::Exception(..) => panic!("Exception"),
+        Fatal::DoubleException(..) => panic!("Double Exception"),
+        Fatal::Interrupt(..) => panic!("Interrupt"),
+        Fatal::StackOutOfBounds(..) => panic!("Stack Out Of Bounds"),
+        Fatal::NestingLimit(..) => panic!("Nesting Limit"),
+        Fatal::CoprocessorSaveAreas(..) => panic!("Coprocessor Save Areas"),
+    }
+}
+
+#[cfg(feature = "defmt")]
+fn panic_with(error: &Fatal) -> ! {
+    defmt::panic!("{}", error)
+}
+
+fn cause_number(cause: &ExceptionCause) -> u32 {
+    // `ExceptionCause` is a fieldless `repr(C)` enum
+    unsafe { *(cause as *const ExceptionCause as *const u32) }
+}
+
+fn decimal(mut value: u32, buffer: &mut [u8; 10]) -> &str {
+    let mut start = buffer.len();
+    loop {
+        start -= 1;
+        buffer[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    unsafe { core::str::from_utf8_unchecked(&buffer[start..]) }
+}
@@ -0,0 +1,524 @@
+//! GDB stub
+//!
+//! With the `gdbstub` feature the debug exception and fatal errors stop in a GDB remote serial
+//! protocol stub, once [`init`] got the transport to talk to GDB over:
+//!
+//! ```ignore
+//! struct Uart;
+//!
+//! impl Transport for Uart {
+//!     fn read(&mut self) -> u8 { /* poll the receive FIFO */ }
+//!     fn write(&mut self, byte: u8) { /* poll the transmit FIFO */ }
+//! }
+//!
+//! static mut UART: Uart = Uart;
+//!
+//! xtensa_lx_rt::gdbstub::init(unsafe { &mut UART });
+//! xtensa_lx_rt::gdbstub::breakpoint(); // wait for GDB
+//! ```
+//!
+//! GDB then connects with `target remote /dev/ttyUSB0`. The transport is used from the debug
+//! exception, so it has to poll and must not rely on interrupts.
+//!
+//! Registers are numbered like GDB's configuration of the chip: pc, ar0-ar63, the special
+//! registers and the FPU registers. Only the window of the interrupted function is available, it
+//! is reported as ar0-ar15 with WINDOWBASE 0 and WINDOWSTART 1, the windows of its callers were
+//! spilled to the stack by the handler.
+//!
+//! Software breakpoints only work in RAM, use `hbreak` for code in flash. The two hardware
+//! breakpoints and single stepping apply to the core that stopped, the other core keeps running.
+//! Memory is accessed word wise, as instruction RAM requires, and only between 0x3C00_0000 and
+//! 0x6000_0000.
+//!
+//! After a fatal error the registers can be inspected but changes are ignored: once GDB continues,
+//! detaches or kills the program, the fatal error is handled as usual.
+
+use core::arch::asm;
+use core::ops::Range;
+
+pub use xtensa_lx_rt_gdbstub::Transport;
+use xtensa_lx_rt_gdbstub::{Breakpoint, Resume, Stub, Target};
+
+use crate::exception::{Context, ExceptionCause};
+use crate::fatal::Fatal;
+use crate::registers::DebugCause;
+
+static mut STUB: Option<Stub<&'static mut dyn Transport>> = None;
+
+/// Talk to GDB over `transport` from now on
+pub fn init(transport: &'static mut dyn Transport) {
+    crate::interrupt::free(|| unsafe { STUB = Some(Stub::new(transport)) });
+}
+
+/// Stop in the stub, e.g. to wait for GDB after [`init`]
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("break 1, 15", options(nostack)) };
+}
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Serve GDB after a debug exception, returns whether the stub is active
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_gdbstub_debug(save_frame: &mut Context) -> u32 {
+    let stub = match unsafe { STUB.as_mut() } {
+        Some(stub) => stub,
+        None => return 0,
+    };
+
+    let cause = DebugCause::read();
+    // stop single stepping
+    unsafe { asm!("wsr.icountlevel {0}", "isync", in(reg) 0, options(nostack)) };
+
+    let resume = stub.run(&mut Frame(save_frame), SIGTRAP);
+    match resume {
+        Resume::Continue(Some(address)) | Resume::Step(Some(address)) => save_frame.PC = address,
+        // continue after a `break` compiled into the program, e.g. by `breakpoint`
+        _ if cause.break_instruction() || cause.break_n_instruction() => skip_break(save_frame),
+        _ => {}
+    }
+    match resume {
+        Resume::Step(_) => step(save_frame),
+        Resume::Kill => loop {
+            unsafe { asm!("waiti 15", options(nostack)) };
+        },
+        _ => {}
+    }
+    1
+}
+
+/// Serve GDB after a fatal error
+pub(crate) fn fatal(error: &Fatal) {
+    if let Some(stub) = unsafe { STUB.as_mut() } {
+        let mut context = *error.context();
+        stub.run(&mut Frame(&mut context), signal(error));
+    }
+}
+
+fn signal(error: &Fatal) -> u8 {
+    match error {
+        Fatal::Exception(cause, _) | Fatal::DoubleException(cause, _) => match cause {
+            ExceptionCause::Illegal => SIGILL,
+            ExceptionCause::DivideByZero => SIGFPE,
+            ExceptionCause::LoadStoreError
+            | ExceptionCause::Unaligned
+            | ExceptionCause::LoadStoreDataError
+            | ExceptionCause::LoadStoreAddrError => SIGBUS,
+            _ => SIGSEGV,
+        },
+        Fatal::Interrupt(..) => SIGINT,
+        Fatal::StackOutOfBounds(..) | Fatal::NestingLimit(..) | Fatal::CoprocessorSaveAreas(..) => {
+            SIGSEGV
+        }
+    }
+}
+
+/// Execute a single instruction of the interrupted code, then take the debug exception again
+fn step(context: &Context) {
+    // count the instructions at the interrupted level, but not the handlers of higher levels
+    let level = (context.PS & 0xf) + 1;
+    unsafe {
+        asm!(
+            "wsr.icount {0}",
+            "wsr.icountlevel {1}",
+            "isync",
+            in(reg) -2i32 as u32,
+            in(reg) level,
+            options(nostack)
+        )
+    };
+}
+
+fn skip_break(context: &mut Context) {
+    if software_breakpoint(context.PC).is_some() {
+        return;
+    }
+    let pc = context.PC;
+    let instruction = (0..3).fold(0, |value, i| value | (read_byte(pc + i) as u32) << (i * 8));
+    if instruction & 0xff_f00f == 0x00_4000 {
+        context.PC += 3;
+    } else if instruction & 0xf0ff == 0xf02d {
+        context.PC += 2;
+    }
+}
+
+/// A register in GDB's numbering
+#[derive(Clone, Copy)]
+enum Register {
+    Pc,
+    Ar(usize),
+    #[cfg(XCHAL_HAVE_LOOPS)]
+    Lbeg,
+    #[cfg(XCHAL_HAVE_LOOPS)]
+    Lend,
+    #[cfg(XCHAL_HAVE_LOOPS)]
+    Lcount,
+    Sar,
+    #[cfg(XCHAL_HAVE_WINDOWED)]
+    WindowBase,
+    #[cfg(XCHAL_HAVE_WINDOWED)]
+    WindowStart,
+    ConfigId0,
+    ConfigId1,
+    Ps,
+    #[cfg(XCHAL_HAVE_THREADPTR)]
+    ThreadPtr,
+    #[cfg(XCHAL_HAVE_BOOLEANS)]
+    Br,
+    #[cfg(XCHAL_HAVE_S32C1I)]
+    Scompare1,
+    #[cfg(XCHAL_HAVE_MAC16)]
+    AccLo,
+    #[cfg(XCHAL_HAVE_MAC16)]
+    AccHi,
+    #[cfg(XCHAL_HAVE_MAC16)]
+    M(usize),
+    #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+    ExpState,
+    #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+    F64rLo,
+    #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+    F64rHi,
+    #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+    F64s,
+    #[cfg(XCHAL_HAVE_FP)]
+    F(usize),
+    #[cfg(XCHAL_HAVE_FP)]
+    Fcr,
+    #[cfg(XCHAL_HAVE_FP)]
+    Fsr,
+}
+
+/// The register with GDB's `number`, or the number of registers
+fn layout(number: usize) -> Result<Register, usize> {
+    let mut first = 0;
+    macro_rules! group {
+        ($count:expr, $register:expr) => {
+            if number < first + $count {
+                return Ok($register(number - first));
+            }
+            first += $count;
+        };
+    }
+
+    group!(1, |_| Register::Pc);
+    group!(64, Register::Ar);
+    #[cfg(XCHAL_HAVE_LOOPS)]
+    {
+        group!(1, |_| Register::Lbeg);
+        group!(1, |_| Register::Lend);
+        group!(1, |_| Register::Lcount);
+    }
+    group!(1, |_| Register::Sar);
+    #[cfg(XCHAL_HAVE_WINDOWED)]
+    {
+        group!(1, |_| Register::WindowBase);
+        group!(1, |_| Register::WindowStart);
+    }
+    group!(1, |_| Register::ConfigId0);
+    group!(1, |_| Register::ConfigId1);
+    group!(1, |_| Register::Ps);
+    #[cfg(XCHAL_HAVE_THREADPTR)]
+    group!(1, |_| Register::ThreadPtr);
+    #[cfg(XCHAL_HAVE_BOOLEANS)]
+    group!(1, |_| Register::Br);
+    #[cfg(XCHAL_HAVE_S32C1I)]
+    group!(1, |_| Register::Scompare1);
+    #[cfg(XCHAL_HAVE_MAC16)]
+    {
+        group!(1, |_| Register::AccLo);
+        group!(1, |_| Register::AccHi);
+        group!(4, Register::M);
+    }
+    #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+    {
+        group!(1, |_| Register::ExpState);
+        group!(1, |_| Register::F64rLo);
+        group!(1, |_| Register::F64rHi);
+        group!(1, |_| Register::F64s);
+    }
+    #[cfg(XCHAL_HAVE_FP)]
+    {
+        group!(16, Register::F);
+        group!(1, |_| Register::Fcr);
+        group!(1, |_| Register::Fsr);
+    }
+    Err(first)
+}
+
+/// The field of `context` holding `register`
+fn field(c: &mut Context, register: Register) -> Option<&mut u32> {
+    Some(match register {
+        Register::Pc => &mut c.PC,
+        Register::Ar(n) => match n {
+            0 => &mut c.A0,
+            1 => &mut c.A1,
+            2 => &mut c.A2,
+            3 => &mut c.A3,
+            4 => &mut c.A4,
+            5 => &mut c.A5,
+            6 => &mut c.A6,
+            7 => &mut c.A7,
+            8 => &mut c.A8,
+            9 => &mut c.A9,
+            10 => &mut c.A10,
+            11 => &mut c.A11,
+            12 => &mut c.A12,
+            13 => &mut c.A13,
+            14 => &mut c.A14,
+            15 => &mut c.A15,
+            _ => return None,
+        },
+        #[cfg(XCHAL_HAVE_LOOPS)]
+        Register::Lbeg => &mut c.LBEG,
+        #[cfg(XCHAL_HAVE_LOOPS)]
+        Register::Lend => &mut c.LEND,
+        #[cfg(XCHAL_HAVE_LOOPS)]
+        Register::Lcount => &mut c.LCOUNT,
+        Register::Sar => &mut c.SAR,
+        Register::Ps => &mut c.PS,
+        #[cfg(XCHAL_HAVE_THREADPTR)]
+        Register::ThreadPtr => &mut c.THREADPTR,
+        #[cfg(XCHAL_HAVE_BOOLEANS)]
+        Register::Br => &mut c.BR,
+        #[cfg(XCHAL_HAVE_S32C1I)]
+        Register::Scompare1 => &mut c.SCOMPARE1,
+        #[cfg(XCHAL_HAVE_MAC16)]
+        Register::AccLo => &mut c.ACCLO,
+        #[cfg(XCHAL_HAVE_MAC16)]
+        Register::AccHi => &mut c.ACCHI,
+        #[cfg(XCHAL_HAVE_MAC16)]
+        Register::M(n) => match n {
+            0 => &mut c.M0,
+            1 => &mut c.M1,
+            2 => &mut c.M2,
+            _ => &mut c.M3,
+        },
+        #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+        Register::F64rLo => &mut c.F64R_LO,
+        #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+        Register::F64rHi => &mut c.F64R_HI,
+        #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+        Register::F64s => &mut c.F64S,
+        #[cfg(XCHAL_HAVE_FP)]
+        Register::F(_) | Register::Fcr | Register::Fsr if !c.fpu_saved() => return None,
+        #[cfg(XCHAL_HAVE_FP)]
+        Register::F(n) => match n {
+            0 => &mut c.F0,
+            1 => &mut c.F1,
+            2 => &mut c.F2,
+            3 => &mut c.F3,
+            4 => &mut c.F4,
+            5 => &mut c.F5,
+            6 => &mut c.F6,
+            7 => &mut c.F7,
+            8 => &mut c.F8,
+            9 => &mut c.F9,
+            10 => &mut c.F10,
+            11 => &mut c.F11,
+            12 => &mut c.F12,
+            13 => &mut c.F13,
+            14 => &mut c.F14,
+            _ => &mut c.F15,
+        },
+        #[cfg(XCHAL_HAVE_FP)]
+        Register::Fcr => &mut c.FCR,
+        #[cfg(XCHAL_HAVE_FP)]
+        Register::Fsr => &mut c.FSR,
+        #[cfg(XCHAL_HAVE_WINDOWED)]
+        Register::WindowBase | Register::WindowStart => return None,
+        #[cfg(XCHAL_HAVE_DFP_ACCEL)]
+        Register::ExpState => return None,
+        Register::ConfigId0 | Register::ConfigId1 => return None,
+    })
+}
+
+/// The saved state of the stopped code
+struct Frame<'a>(&'a mut Context);
+
+impl Target for Frame<'_> {
+    fn register_count(&self) -> usize {
+        layout(usize::MAX).err().unwrap_or(0)
+    }
+
+    fn read_register(&mut self, number: usize) -> Option<u32> {
+        match layout(number).ok()? {
+            #[cfg(XCHAL_HAVE_WINDOWED)]
+            Register::WindowBase => Some(0),
+            #[cfg(XCHAL_HAVE_WINDOWED)]
+            Register::WindowStart => Some(1),
+            register => field(self.0, register).map(|value| *value),
+        }
+    }
+
+    fn write_register(&mut self, number: usize, value: u32) -> bool {
+        let slot = layout(number)
+            .ok()
+            .and_then(|register| field(self.0, register));
+        match slot {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> bool {
+        if !accessible(address, buffer.len()) {
+            return false;
+        }
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = read_byte(address + i as u32);
+        }
+        true
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+        accessible(address, data.len()) && write(address, data)
+    }
+
+    fn add_breakpoint(&mut self, kind: Breakpoint, address: u32) -> bool {
+        match kind {
+            Breakpoint::Software { length } => add_software_breakpoint(address, length),
+            Breakpoint::Hardware => set_hardware_breakpoint(Some(address), address),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, kind: Breakpoint, address: u32) -> bool {
+        match kind {
+            Breakpoint::Software { .. } => remove_software_breakpoint(address),
+            Breakpoint::Hardware => set_hardware_breakpoint(None, address),
+        }
+    }
+}
+
+/// Memory the stub accesses, internal and external memory and peripherals
+const MEMORY: Range<u32> = 0x3C00_0000..0x6000_0000;
+
+fn accessible(address: u32, len: usize) -> bool {
+    MEMORY.contains(&address) && address as u64 + len as u64 <= MEMORY.end as u64
+}
+
+// instruction RAM only supports aligned word accesses
+
+fn read_byte(address: u32) -> u8 {
+    let word = unsafe { ((address & !3) as *const u32).read_volatile() };
+    (word >> ((address & 3) * 8)) as u8
+}
+
+/// Write `data` to `address`, returns whether it reads back, which it doesn't in flash
+fn write(address: u32, data: &[u8]) -> bool {
+    for (i, &byte) in data.iter().enumerate() {
+        let address = address + i as u32;
+        let word = (address & !3) as *mut u32;
+        let shift = (address & 3) * 8;
+        unsafe {
+            let value = word.read_volatile() & !(0xff << shift) | (byte as u32) << shift;
+            word.write_volatile(value);
+        }
+    }
+    // the memory may hold code
+    unsafe { asm!("isync", options(nostack)) };
+
+    data.iter()
+        .enumerate()
+        .all(|(i, &byte)| read_byte(address + i as u32) == byte)
+}
+
+/// `break 1, 1`
+const BREAK: [u8; 3] = [0x10, 0x41, 0x00];
+/// `break.n 1`
+const BREAK_N: [u8; 2] = [0x2d, 0xf1];
+
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: u32,
+    original: [u8; 3],
+    length: usize,
+}
+
+static mut SOFTWARE_BREAKPOINTS: [Option<SoftwareBreakpoint>; 16] = [None; 16];
+
+fn software_breakpoint(address: u32) -> Option<&'static mut Option<SoftwareBreakpoint>> {
+    unsafe { SOFTWARE_BREAKPOINTS.iter_mut() }
+        .find(|slot| matches!(slot, Some(breakpoint) if breakpoint.address == address))
+}
+
+fn add_software_breakpoint(address: u32, length: u32) -> bool {
+    let instruction = match length {
+        2 => &BREAK_N[..],
+        _ => &BREAK[..],
+    };
+    if software_breakpoint(address).is_some() {
+        return true;
+    }
+    if !accessible(address, instruction.len()) {
+        return false;
+    }
+    let slot = match unsafe { SOFTWARE_BREAKPOINTS.iter_mut() }.find(|slot| slot.is_none()) {
+        Some(slot) => slot,
+        None => return false,
+    };
+
+    let mut original = [0; 3];
+    for (i, byte) in original[..instruction.len()].iter_mut().enumerate() {
+        *byte = read_byte(address + i as u32);
+    }
+    if !write(address, instruction) {
+        write(address, &original[..instruction.len()]);
+        return false;
+    }
+    *slot = Some(SoftwareBreakpoint {
+        address,
+        original,
+        length: instruction.len(),
+    });
+    true
+}
+
+fn remove_software_breakpoint(address: u32) -> bool {
+    match software_breakpoint(address) {
+        Some(slot) => {
+            let breakpoint = slot.take().unwrap();
+            write(address, &breakpoint.original[..breakpoint.length])
+        }
+        None => false,
+    }
+}
+
+/// The addresses of IBREAKA0 and IBREAKA1 of each core
+static mut HARDWARE_BREAKPOINTS: [[Option<u32>; 2]; 2] = [[None; 2]; 2];
+
+/// Set the hardware breakpoint at `address`, or clear it with `None`
+fn set_hardware_breakpoint(set: Option<u32>, address: u32) -> bool {
+    let slots = unsafe { &mut HARDWARE_BREAKPOINTS[crate::core_id()] };
+    let slot = match set {
+        Some(_) => slots.iter().position(|slot| slot.is_none()),
+        None => slots.iter().position(|slot| *slot == Some(address)),
+    };
+    let slot = match slot {
+        Some(slot) => slot,
+        None => return false,
+    };
+    slots[slot] = set;
+
+    let enable = slots.iter().enumerate().fold(0u32, |enable, (i, slot)| {
+        enable | (slot.is_some() as u32) << i
+    });
+    unsafe {
+        match slot {
+            0 => asm!("wsr.ibreaka0 {0}", in(reg) address, options(nostack)),
+            _ => asm!("wsr.ibreaka1 {0}", in(reg) address, options(nostack)),
+        }
+        asm!("wsr.ibreakenable {0}", "isync", in(reg) enable, options(nostack));
+    }
+    true
+}
@@ -0,0 +1,114 @@
+//! Low power waiting for interrupts
+//!
+//! [`idle_if`] is meant for executors and main loops without work: it checks the sleep condition
+//! with the interrupts handled in Rust (up to `PS_INTLEVEL_EXCM`) masked and only then executes
+//! `waiti` with the caller's PS.INTLEVEL (`waiti 0` in thread mode), which unmasks them and stops
+//! the core atomically. An interrupt that makes the condition false in between stays pending and
+//! ends the `waiti` immediately, so its wakeup can't be lost. PS.INTLEVEL is only ever raised, so
+//! interrupts the caller masked stay masked.
+//!
+//! The hook defined with `#[idle]` is called with [`IdlePhase::Enter`] right before sleeping and
+//! with [`IdlePhase::Exit`] after waking up, both with the same interrupts masked. The waking
+//! interrupt has already been handled when `Exit` is called. A tickless system can use the hook to
+//! reprogram its timers.
+//!
+//! When the condition can be changed by handlers above `PS_INTLEVEL_EXCM`, [`idle_if_masked`]
+//! masks up to their level instead.
+
+use core::arch::asm;
+
+use crate::registers::Ps;
+
+/// When the `#[idle]` hook is called
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePhase {
+    /// The core is about to sleep
+    Enter,
+    /// The core woke up
+    Exit,
+}
+
+extern "Rust" {
+    /// This symbol will be provided by the user via `#[idle]`
+    fn __idle_hook(phase: IdlePhase);
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[rustfmt::skip]
+pub extern "Rust" fn default_idle_hook(_phase: IdlePhase) {}
+
+/// Sleep until an interrupt above `level` is taken
+///
+/// PS.INTLEVEL is `level` while waiting and restored to its previous value once the interrupt
+/// handler returned.
+///
+/// # Panics
+///
+/// Panics if `level` is above 15.
+#[inline]
+pub fn wait_for_interrupt(level: u32) {
+    unsafe {
+        let ps = Ps::read();
+        match level {
+            0 => asm!("waiti 0", options(nostack)),
+            1 => asm!("waiti 1", options(nostack)),
+            2 => asm!("waiti 2", options(nostack)),
+            3 => asm!("waiti 3", options(nostack)),
+            4 => asm!("waiti 4", options(nostack)),
+            5 => asm!("waiti 5", options(nostack)),
+            6 => asm!("waiti 6", options(nostack)),
+            7 => asm!("waiti 7", options(nostack)),
+            8 => asm!("waiti 8", options(nostack)),
+            9 => asm!("waiti 9", options(nostack)),
+            10 => asm!("waiti 10", options(nostack)),
+            11 => asm!("waiti 11", options(nostack)),
+            12 => asm!("waiti 12", options(nostack)),
+            13 => asm!("waiti 13", options(nostack)),
+            14 => asm!("waiti 14", options(nostack)),
+            15 => asm!("waiti 15", options(nostack)),
+            _ => panic!("Invalid interrupt level {}", level),
+        }
+        ps.write();
+    }
+}
+
+/// Sleep until the next interrupt, calling the `#[idle]` hook around it
+#[inline]
+pub fn idle() {
+    idle_if(|| true);
+}
+
+/// Sleep until the next interrupt if `condition` returns `true`
+///
+/// `condition` is evaluated with all interrupts up to `PS_INTLEVEL_EXCM` masked, see the
+/// [module documentation](self). Returns whether the core slept.
+#[inline]
+pub fn idle_if(condition: impl FnOnce() -> bool) -> bool {
+    // PS_INTLEVEL_EXCM: interrupt handlers above this level shouldn't be written in Rust
+    idle_if_masked(3, condition)
+}
+
+/// Like [`idle_if`], but with all interrupts up to `level` masked while `condition` is evaluated
+///
+/// PS.INTLEVEL is not lowered when it is above `level` already.
+///
+/// # Panics
+///
+/// Panics if `level` is above 15.
+pub fn idle_if_masked(level: u32, condition: impl FnOnce() -> bool) -> bool {
+    unsafe {
+        let ps = crate::interrupt::raise_intlevel(level);
+
+        let sleep = condition();
+        if sleep {
+            __idle_hook(IdlePhase::Enter);
+            // only unmask the interrupts the caller had unmasked, PS is raised again afterwards
+            wait_for_interrupt(ps.intlevel());
+            __idle_hook(IdlePhase::Exit);
+        }
+
+        ps.write();
+        sleep
+    }
+}
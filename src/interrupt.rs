@@ -1,2 +1,190 @@
 #[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
 include!(concat!(env!("OUT_DIR"), "/interrupt_level_masks.rs"));
+
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+use core::arch::asm;
+
+use crate::registers::Ps;
+
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+mod guard;
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+mod signal;
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+pub use signal::{park, InterruptSignal, Wait};
+
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "storm-detection"
+))]
+pub use guard::set_storm_limit;
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+pub(crate) use guard::spurious;
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+pub use guard::{
+    disabled, reenable, set_spurious_policy, spurious_count, InterruptFault, InterruptFaultKind,
+    SpuriousPolicy,
+};
+
+/// Run a level 1 interrupt handler while allowing other level 1 interrupts to preempt it
+///
+/// All pending and enabled level 1 interrupts are masked in INTENABLE and PS.INTLEVEL is lowered
+/// to 0 while `f` runs: `f` may service any of them, e.g. as a dispatcher, and a source that is
+/// still pending can't enter it again. Once `f` returns PS.INTLEVEL is raised back to 1 and the
+/// masked interrupts are enabled again, except the ones disabled in the meantime, by `f` with
+/// [`disable`] or by the runtime (see [`disabled`]).
+///
+/// A plain `wsr.intenable` doesn't see the masked interrupts, they read as disabled already: a
+/// handler that keeps its own source disabled until e.g. a task re-arms it has to use [`disable`],
+/// or the source is enabled again when the handler returns.
+///
+/// When no level 1 interrupt is pending, e.g. because the source was cleared by a handler of a
+/// higher level in the meantime, `f` runs with PS.INTLEVEL untouched, i.e. without preemption.
+///
+/// Used by `#[interrupt(1, nested)]`
+#[doc(hidden)]
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+#[inline(always)]
+pub unsafe fn __nested_level_1_interrupt<F: FnOnce()>(f: F) {
+    let pending = interrupt() & intenable() & CpuInterruptLevel::Level1.mask();
+    if pending == 0 {
+        // nothing to preempt for, run the handler with PS.INTLEVEL untouched
+        f();
+        return;
+    }
+
+    free(|| {
+        NESTED_MASKED[crate::core_id()] |= pending;
+        asm!("wsr.intenable {0}", in(reg) intenable() & !pending, options(nostack));
+    });
+
+    let ps = rsil(0);
+
+    f();
+
+    ps.write();
+
+    free(|| {
+        let masked = &mut NESTED_MASKED[crate::core_id()];
+        // `modify_intenable` already dropped the ones disabled while `f` ran
+        let restore = *masked & pending & !disabled();
+        *masked &= !pending;
+        asm!("wsr.intenable {0}", in(reg) intenable() | restore, options(nostack));
+    });
+}
+
+/// Level 1 interrupts of each core masked in INTENABLE by `__nested_level_1_interrupt`
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+static mut NESTED_MASKED: [u32; 2] = [0; 2];
+
+/// Enable `interrupt` in INTENABLE of the current core
+///
+/// # Safety
+///
+/// Enabling an interrupt can break critical sections relying on it being disabled.
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+pub unsafe fn enable(interrupt: u32) {
+    modify_intenable(|enabled| enabled | (1 << (interrupt & 31)));
+}
+
+/// Disable `interrupt` in INTENABLE of the current core
+///
+/// Unlike a plain INTENABLE write this also keeps an interrupt disabled that is masked by a
+/// running `#[interrupt(1, nested)]` handler.
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+pub fn disable(interrupt: u32) {
+    unsafe { modify_intenable(|enabled| enabled & !(1 << (interrupt & 31))) };
+}
+
+// We redefine these functions to avoid pulling in `xtensa-lx` as a dependency:
+
+/// Raise PS.INTLEVEL to `level` and return the previous PS
+///
+/// # Panics
+///
+/// Panics if `level` is above 15.
+#[inline(always)]
+pub(crate) unsafe fn rsil(level: u32) -> Ps {
+    use core::arch::asm;
+
+    let ps: u32;
+    match level {
+        0 => asm!("rsil {0}, 0", out(reg) ps, options(nostack)),
+        1 => asm!("rsil {0}, 1", out(reg) ps, options(nostack)),
+        2 => asm!("rsil {0}, 2", out(reg) ps, options(nostack)),
+        3 => asm!("rsil {0}, 3", out(reg) ps, options(nostack)),
+        4 => asm!("rsil {0}, 4", out(reg) ps, options(nostack)),
+        5 => asm!("rsil {0}, 5", out(reg) ps, options(nostack)),
+        6 => asm!("rsil {0}, 6", out(reg) ps, options(nostack)),
+        7 => asm!("rsil {0}, 7", out(reg) ps, options(nostack)),
+        8 => asm!("rsil {0}, 8", out(reg) ps, options(nostack)),
+        9 => asm!("rsil {0}, 9", out(reg) ps, options(nostack)),
+        10 => asm!("rsil {0}, 10", out(reg) ps, options(nostack)),
+        11 => asm!("rsil {0}, 11", out(reg) ps, options(nostack)),
+        12 => asm!("rsil {0}, 12", out(reg) ps, options(nostack)),
+        13 => asm!("rsil {0}, 13", out(reg) ps, options(nostack)),
+        14 => asm!("rsil {0}, 14", out(reg) ps, options(nostack)),
+        15 => asm!("rsil {0}, 15", out(reg) ps, options(nostack)),
+        _ => panic!("Invalid interrupt level {}", level),
+    }
+    Ps(ps)
+}
+
+/// Raise PS.INTLEVEL to at least `level` and return the previous PS
+///
+/// Unlike [`rsil`] this never lowers PS.INTLEVEL, e.g. when called in a critical section or from a
+/// handler of a higher level.
+///
+/// # Panics
+///
+/// Panics if `level` is above 15.
+#[inline]
+pub(crate) unsafe fn raise_intlevel(level: u32) -> Ps {
+    let ps = Ps::read();
+    if ps.intlevel() < level {
+        rsil(level)
+    } else {
+        ps
+    }
+}
+
+/// Run `f` with all maskable interrupts disabled
+#[inline(always)]
+pub(crate) fn free<R>(f: impl FnOnce() -> R) -> R {
+    let ps = unsafe { rsil(15) };
+    let result = f();
+    unsafe { ps.write() };
+    result
+}
+
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+#[inline(always)]
+unsafe fn interrupt() -> u32 {
+    let interrupt: u32;
+    asm!("rsr.interrupt {0}", out(reg) interrupt, options(nostack));
+    interrupt
+}
+
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+#[inline(always)]
+unsafe fn intenable() -> u32 {
+    let intenable: u32;
+    asm!("rsr.intenable {0}", out(reg) intenable, options(nostack));
+    intenable
+}
+
+/// Read-modify-write INTENABLE with all maskable interrupts disabled, so that a higher priority
+/// handler changing INTENABLE in between is not lost
+///
+/// `f` sees the interrupts masked by nested level 1 handlers as enabled. They stay masked until
+/// their handler returns, unless `f` disables them.
+#[cfg(any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"))]
+#[inline(always)]
+pub(crate) unsafe fn modify_intenable(f: impl FnOnce(u32) -> u32) {
+    let ps = rsil(15);
+    let masked = &mut NESTED_MASKED[crate::core_id()];
+    let intenable = f(intenable() | *masked);
+    *masked &= intenable;
+    asm!("wsr.intenable {0}", in(reg) intenable & !*masked, options(nostack));
+    ps.write();
+}
@@ -0,0 +1,249 @@
+//! Spurious and stuck interrupt handling
+//!
+//! An interrupt of a level without a `#[interrupt(level)]` handler is spurious. By default it
+//! panics, [`set_spurious_policy`] can instead count and ignore it or disable its source.
+//!
+//! With the `storm-detection` feature every entry of a level 1-7 handler is counted against the
+//! interrupts pending at that level. An interrupt taken more than `entries` times within
+//! `window` cycles, see [`set_storm_limit`], is considered stuck and disabled in INTENABLE before
+//! the handler runs. Fast handlers (`#[interrupt(level, fast)]`) are not watched.
+//!
+//! Spurious and stuck interrupts are reported to the hook defined with `#[interrupt_fault]`,
+//! disabled ones can be enabled again with [`reenable`].
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{free, intenable, interrupt, modify_intenable, INTCLEAR_MASK};
+use crate::exception::Context;
+
+/// What to do with an interrupt of a level without handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpuriousPolicy {
+    /// Report it and handle it as [fatal error](crate::fatal), by default a panic
+    Panic,
+    /// Report and count it, and clear it if it is edge triggered or a software interrupt
+    ///
+    /// Other sources, e.g. level triggered ones, can't be cleared and would be taken again right
+    /// away, so they are disabled like with [`Disable`](SpuriousPolicy::Disable).
+    Ignore,
+    /// Report and count it, and disable it in INTENABLE
+    Disable,
+}
+
+/// Why an interrupt is reported to the `#[interrupt_fault]` hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InterruptFaultKind {
+    /// Taken at a level without handler
+    Spurious,
+    /// Taken too often, see [`set_storm_limit`]
+    Storm,
+}
+
+/// An interrupt reported to the `#[interrupt_fault]` hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterruptFault {
+    pub kind: InterruptFaultKind,
+    /// CPU interrupt number
+    pub interrupt: u32,
+    pub level: u32,
+    /// Whether the interrupt was disabled in INTENABLE
+    pub disabled: bool,
+}
+
+extern "Rust" {
+    /// This symbol will be provided by the user via `#[interrupt_fault]`
+    fn __interrupt_fault_hook(fault: InterruptFault);
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[rustfmt::skip]
+pub extern "Rust" fn default_interrupt_fault_hook(_fault: InterruptFault) {
+    #[cfg(feature = "defmt")]
+    defmt::warn!("{}", _fault);
+}
+
+static POLICY: AtomicU32 = AtomicU32::new(SpuriousPolicy::Panic as u32);
+static DISABLED: AtomicU32 = AtomicU32::new(0);
+static mut SPURIOUS: [u32; 32] = [0; 32];
+
+/// Set how spurious interrupts are handled
+pub fn set_spurious_policy(policy: SpuriousPolicy) {
+    POLICY.store(policy as u32, Ordering::Relaxed);
+}
+
+/// Number of times `interrupt` was taken without handler
+pub fn spurious_count(interrupt: u32) -> u32 {
+    free(|| unsafe { SPURIOUS[interrupt as usize & 31] })
+}
+
+/// Interrupts disabled because they were spurious or stuck, as INTENABLE mask
+pub fn disabled() -> u32 {
+    DISABLED.load(Ordering::Relaxed)
+}
+
+/// Enable `interrupt` again on the current core after it was disabled by the runtime
+pub fn reenable(interrupt: u32) {
+    let bit = 1 << (interrupt & 31);
+    free(|| {
+        #[cfg(feature = "storm-detection")]
+        unsafe {
+            STORMS[crate::core_id()][interrupt as usize & 31] = Window::NEW;
+        }
+        DISABLED.store(DISABLED.load(Ordering::Relaxed) & !bit, Ordering::Relaxed);
+    });
+    unsafe { modify_intenable(|enabled| enabled | bit) };
+}
+
+/// Called by the default handler of the levels without `#[interrupt(level)]` handler
+pub(crate) fn spurious(level: u32, save_frame: &Context) {
+    let policy = POLICY.load(Ordering::Relaxed);
+    let pending = unsafe { interrupt() & intenable() } & level_mask(level);
+
+    for interrupt in bits(pending) {
+        free(|| unsafe { SPURIOUS[interrupt as usize] += 1 });
+        let disable = policy == SpuriousPolicy::Disable as u32
+            || (policy == SpuriousPolicy::Ignore as u32 && INTCLEAR_MASK & (1 << interrupt) == 0);
+        if disable {
+            self::disable(interrupt);
+        } else {
+            clear(interrupt);
+        }
+        unsafe {
+            __interrupt_fault_hook(InterruptFault {
+                kind: InterruptFaultKind::Spurious,
+                interrupt,
+                level,
+                disabled: disable,
+            })
+        };
+    }
+
+    if policy == SpuriousPolicy::Panic as u32 {
+        crate::fatal::fatal(crate::fatal::Fatal::Interrupt(level, save_frame))
+    }
+}
+
+#[cfg(feature = "storm-detection")]
+static STORM_ENTRIES: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "storm-detection")]
+static STORM_WINDOW: AtomicU32 = AtomicU32::new(0);
+
+/// Entries of one interrupt within the current window
+#[cfg(feature = "storm-detection")]
+#[derive(Clone, Copy)]
+struct Window {
+    start: u32,
+    entries: u32,
+}
+
+#[cfg(feature = "storm-detection")]
+impl Window {
+    const NEW: Window = Window {
+        start: 0,
+        entries: 0,
+    };
+}
+
+// Every interrupt has a single level, so each window is only touched by the handlers of one level
+#[cfg(feature = "storm-detection")]
+static mut STORMS: [[Window; 32]; 2] = [[Window::NEW; 32]; 2];
+
+/// Disable interrupts taken more than `entries` times within `window` cycles, 0 entries turns
+/// the detection off (the default)
+#[cfg(feature = "storm-detection")]
+pub fn set_storm_limit(entries: u32, window: u32) {
+    STORM_WINDOW.store(window, Ordering::Relaxed);
+    STORM_ENTRIES.store(entries, Ordering::Relaxed);
+}
+
+/// Count the entry of `level` and disable the stuck interrupts, returns whether any interrupt of
+/// `level` is still pending
+#[cfg(feature = "storm-detection")]
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_storm_check(level: u32) -> u32 {
+    let limit = STORM_ENTRIES.load(Ordering::Relaxed);
+    if limit == 0 {
+        return 1;
+    }
+    let mask = level_mask(level);
+    let window = STORM_WINDOW.load(Ordering::Relaxed);
+    let now = ccount();
+
+    let pending = unsafe { interrupt() & intenable() } & mask;
+    for interrupt in bits(pending) {
+        let stuck = unsafe {
+            let state = &mut STORMS[crate::core_id()][interrupt as usize];
+            if state.entries == 0 || now.wrapping_sub(state.start) > window {
+                state.start = now;
+                state.entries = 0;
+            }
+            state.entries += 1;
+            state.entries > limit
+        };
+        if stuck {
+            disable(interrupt);
+            unsafe {
+                __interrupt_fault_hook(InterruptFault {
+                    kind: InterruptFaultKind::Storm,
+                    interrupt,
+                    level,
+                    disabled: true,
+                })
+            };
+        }
+    }
+
+    (unsafe { interrupt() & intenable() } & mask != 0) as u32
+}
+
+fn disable(interrupt: u32) {
+    let bit = 1 << interrupt;
+    free(|| DISABLED.store(DISABLED.load(Ordering::Relaxed) | bit, Ordering::Relaxed));
+    unsafe { modify_intenable(|enabled| enabled & !bit) };
+    clear(interrupt);
+}
+
+/// Clear an edge triggered or software interrupt, level triggered ones are unaffected
+fn clear(interrupt: u32) {
+    unsafe { asm!("wsr.intclear {0}", in(reg) 1u32 << interrupt, options(nostack)) };
+}
+
+fn level_mask(level: u32) -> u32 {
+    match level {
+        1 => super::CpuInterruptLevel::Level1.mask(),
+        2 => super::CpuInterruptLevel::Level2.mask(),
+        3 => super::CpuInterruptLevel::Level3.mask(),
+        4 => super::CpuInterruptLevel::Level4.mask(),
+        5 => super::CpuInterruptLevel::Level5.mask(),
+        6 => super::CpuInterruptLevel::Level6.mask(),
+        7 => super::CpuInterruptLevel::Level7.mask(),
+        _ => 0,
+    }
+}
+
+/// The numbers of the bits set in `mask`
+fn bits(mut mask: u32) -> impl Iterator<Item = u32> {
+    core::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let bit = mask.trailing_zeros();
+        mask &= mask - 1;
+        Some(bit)
+    })
+}
+
+#[cfg(feature = "storm-detection")]
+#[inline(always)]
+fn ccount() -> u32 {
+    let ccount: u32;
+    unsafe { asm!("rsr.ccount {0}", out(reg) ccount, options(nostack)) };
+    ccount
+}
@@ -0,0 +1,135 @@
+//! Interrupt driven wakers for async code
+//!
+//! An [`InterruptSignal`] is signaled by an interrupt handler and awaited by a future. Its state is
+//! only touched with PS.INTLEVEL raised to at least the level of the signaling handler, so it may
+//! be shared with handlers of any level handled in Rust, not just level 1. PS.INTLEVEL is never
+//! lowered, so the signal can also be used in critical sections and handlers of higher levels.
+//!
+//! An executor without ready tasks calls [`park`] with the highest level of its signals: the run
+//! queue is checked with those interrupts masked and the core only sleeps if it is still empty.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use super::{raise_intlevel, CpuInterruptLevel};
+
+struct State {
+    signaled: bool,
+    waker: Option<Waker>,
+}
+
+/// A flag set by an interrupt handler, which wakes the task waiting for it
+///
+/// ```ignore
+/// static UART_RX: InterruptSignal = InterruptSignal::new(CpuInterruptLevel::Level1);
+///
+/// #[interrupt(1)]
+/// fn level_1_interrupt() {
+///     UART_RX.signal();
+/// }
+///
+/// async fn receive() {
+///     UART_RX.wait().await;
+/// }
+/// ```
+///
+/// The waker is woken from the interrupt handler, so the executor's wake has to be safe to call at
+/// that level.
+pub struct InterruptSignal {
+    level: CpuInterruptLevel,
+    state: UnsafeCell<State>,
+}
+
+// The state is only accessed with interrupts up to `level` masked
+unsafe impl Sync for InterruptSignal {}
+
+impl InterruptSignal {
+    /// Create a signal for a handler of `level`
+    ///
+    /// `level` must be at least the level of the handler calling [`signal`](Self::signal). The
+    /// NMI level can't be masked and must not be used.
+    pub const fn new(level: CpuInterruptLevel) -> Self {
+        InterruptSignal {
+            level,
+            state: UnsafeCell::new(State {
+                signaled: false,
+                waker: None,
+            }),
+        }
+    }
+
+    /// Set the signal and wake the waiting task
+    pub fn signal(&self) {
+        let waker = self.with_state(|state| {
+            state.signaled = true;
+            state.waker.take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Clear the signal without waking anyone
+    pub fn reset(&self) {
+        self.with_state(|state| state.signaled = false);
+    }
+
+    /// Whether the signal is set
+    pub fn signaled(&self) -> bool {
+        self.with_state(|state| state.signaled)
+    }
+
+    /// Clear the signal if it is set, or register the waker of `cx` to be woken once it is
+    pub fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.with_state(|state| {
+            if state.signaled {
+                state.signaled = false;
+                Poll::Ready(())
+            } else {
+                match &state.waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => state.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Wait until the signal is set and clear it
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { signal: self }
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        unsafe {
+            let ps = raise_intlevel(self.level.level());
+            let result = f(&mut *self.state.get());
+            ps.write();
+            result
+        }
+    }
+}
+
+/// Future returned by [`InterruptSignal::wait`]
+pub struct Wait<'a> {
+    signal: &'a InterruptSignal,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.signal.poll_wait(cx)
+    }
+}
+
+/// Sleep until the next interrupt unless `has_work` returns `true`
+///
+/// `has_work` is evaluated with all interrupts up to `level` masked, so a signal from a handler up
+/// to that level is either seen by it or wakes the core right away. The `#[idle]` hook is called
+/// around the sleep. Returns whether the core slept.
+pub fn park(level: CpuInterruptLevel, has_work: impl FnOnce() -> bool) -> bool {
+    crate::idle::idle_if_masked(level.level(), || !has_work())
+}
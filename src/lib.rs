@@ -4,13 +4,53 @@
 // required due to: https://github.com/rust-lang/rust/pull/87324
 #![allow(named_asm_labels)]
 
-use core::arch::asm;
-
 pub use r0::{init_data, zero_bss};
-pub use xtensa_lx_rt_proc_macros::{entry, exception, interrupt, pre_init};
+pub use xtensa_lx_rt_proc_macros::{
+    double_exception, entry, exception, fatal_handler, idle, interrupt, interrupt_fault, pre_init,
+    syscall,
+};
 
 pub mod exception;
+pub mod fatal;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "gdbstub"
+))]
+pub mod gdbstub;
+pub mod idle;
 pub mod interrupt;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "profiler"
+))]
+pub mod profiler;
+pub mod registers;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "semihosting"
+))]
+pub mod semihosting;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "stats"
+))]
+pub mod stats;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    feature = "syscall"
+))]
+pub mod syscall;
+#[cfg(all(
+    any(feature = "esp32", feature = "esp32s2", feature = "esp32s3"),
+    any(
+        XCHAL_HAVE_TIMER0,
+        XCHAL_HAVE_TIMER1,
+        XCHAL_HAVE_TIMER2,
+        XCHAL_HAVE_TIMER3
+    )
+))]
+pub mod systick;
+pub mod time;
 
 #[doc(hidden)]
 #[no_mangle]
@@ -63,7 +103,7 @@ pub unsafe extern "C" fn Reset() -> ! {
     reset_internal_timers();
 
     // move vec table
-    set_vecbase(&_init_start as *const u32);
+    registers::set_vecbase(&_init_start as *const u32);
 
     __post_init();
 
@@ -87,6 +127,8 @@ unsafe fn reset_internal_timers() {
         XCHAL_HAVE_TIMER3
     ))]
     {
+        use core::arch::asm;
+
         let value = 0;
         cfg_asm!(
         {
@@ -126,10 +168,19 @@ extern "C" {
     pub fn NMI(level: u32, save_frame: &mut crate::exception::Context);
 }
 
+/// Index of the core this code runs on (PRID bit 13 on the dual core chips)
+#[doc(hidden)]
+#[inline]
+#[cfg(XCHAL_HAVE_PRID)]
+pub(crate) fn core_id() -> usize {
+    ((registers::prid() >> 13) & 1) as usize
+}
+
 #[doc(hidden)]
 #[inline]
-unsafe fn set_vecbase(base: *const u32) {
-    asm!("wsr.vecbase {0}", in(reg) base, options(nostack));
+#[cfg(not(XCHAL_HAVE_PRID))]
+pub(crate) fn core_id() -> usize {
+    0
 }
 
 #[doc(hidden)]
@@ -157,3 +208,22 @@ macro_rules! cfg_asm {
         cfg_asm!(@inner, [], [$($opts)*], $($asms)*)
     };
 }
+
+#[macro_export]
+macro_rules! cfg_global_asm {
+    (@inner, [$($x:tt)*], ) => {
+        global_asm!($($x)*);
+    };
+    (@inner, [$($x:tt)*], #[cfg($meta:meta)] $asm:literal, $($rest:tt)*) => {
+        #[cfg($meta)]
+        cfg_global_asm!(@inner, [$($x)* $asm,], $($rest)*);
+        #[cfg(not($meta))]
+        cfg_global_asm!(@inner, [$($x)*], $($rest)*);
+    };
+    (@inner, [$($x:tt)*], $asm:literal, $($rest:tt)*) => {
+        cfg_global_asm!(@inner, [$($x)* $asm,], $($rest)*);
+    };
+    ({$($asms:tt)*}) => {
+        cfg_global_asm!(@inner, [], $($asms)*);
+    };
+}
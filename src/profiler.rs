@@ -0,0 +1,235 @@
+//! Sampling profiler
+//!
+//! [`sample`] records the interrupted PC, and optionally a short backtrace, into a ring buffer of
+//! the current core. It can be called from any interrupt handler that gets the frame, e.g. a
+//! timer interrupt. With [`start`] the performance counter raises the Profiling interrupt every
+//! `period` cycles instead, which the runtime routes to the sampler before the handler of its
+//! level.
+//!
+//! The samples are read with [`drain`]. Their [`Display`](core::fmt::Display) implementation
+//! prints one `PROF <pc> <return address>...` line per sample, the `xtensa-lx-rt-profiler` host
+//! tool symbolizes such a log against the ELF file into folded stacks for flamegraphs.
+//!
+//! Backtraces are best effort: they follow the return addresses and stack pointers spilled to the
+//! base save areas of the windowed ABI and stop at the first implausible stack pointer.
+
+#[cfg(XCHAL_HAVE_PROFILING)]
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::exception::Context;
+use crate::interrupt::free;
+
+#[cfg(XCHAL_HAVE_PROFILING)]
+include!(concat!(env!("OUT_DIR"), "/profiling.rs"));
+
+/// Maximum number of addresses of a sample, the PC included
+pub const MAX_DEPTH: usize = 8;
+
+/// Samples buffered per core
+const CAPACITY: usize = 256;
+
+/// Addresses stack pointers of backtraces are accepted from (internal and external data RAM)
+const STACK_RANGE: core::ops::Range<u32> = 0x3C00_0000..0x4000_0000;
+
+/// The PC and return addresses of an interrupted context, innermost first
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    addresses: [u32; MAX_DEPTH],
+    len: usize,
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        addresses: [0; MAX_DEPTH],
+        len: 0,
+    };
+
+    pub fn addresses(&self) -> &[u32] {
+        &self.addresses[..self.len]
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PROF")?;
+        for address in self.addresses() {
+            write!(f, " {:08x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// Single producer (the interrupt handlers of one core, serialized by masking interrupts), single
+/// consumer ring buffer
+struct Ring {
+    samples: UnsafeCell<[Sample; CAPACITY]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            samples: UnsafeCell::new([Sample::EMPTY; CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+}
+
+static RINGS: [Ring; 2] = [Ring::new(), Ring::new()];
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static BACKTRACE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Record up to `depth` return addresses in addition to the PC, at most [`MAX_DEPTH`] - 1
+pub fn set_backtrace_depth(depth: usize) {
+    BACKTRACE_DEPTH.store(depth.min(MAX_DEPTH - 1), Ordering::Relaxed);
+}
+
+/// Number of samples lost because the ring buffer was full
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Record the context interrupted by the current handler
+pub fn sample(frame: &Context) {
+    let mut sample = Sample::EMPTY;
+    sample.addresses[0] = frame.PC;
+    sample.len = 1;
+
+    let depth = BACKTRACE_DEPTH.load(Ordering::Relaxed);
+    let mut pc = frame.PC;
+    let mut ra = frame.A0;
+    let mut sp = frame.A1;
+    while sample.len <= depth && ra != 0 {
+        // the upper two bits of a windowed return address hold the call size, not the address
+        let address = (ra & 0x3fff_ffff) | (pc & 0xc000_0000);
+        sample.addresses[sample.len] = address;
+        sample.len += 1;
+
+        if !STACK_RANGE.contains(&sp) || sp % 16 != 0 {
+            break;
+        }
+        // the caller's A0 and A1 are spilled below the stack pointer of the callee
+        let (next_ra, next_sp) = unsafe {
+            (
+                ((sp - 16) as *const u32).read_volatile(),
+                ((sp - 12) as *const u32).read_volatile(),
+            )
+        };
+        if next_sp <= sp {
+            break;
+        }
+        pc = address;
+        ra = next_ra;
+        sp = next_sp;
+    }
+
+    free(|| {
+        let ring = &RINGS[crate::core_id()];
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == CAPACITY {
+            DROPPED.store(DROPPED.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { (*ring.samples.get())[head % CAPACITY] = sample };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+    });
+}
+
+/// Remove all buffered samples of both cores, passing each to `f`
+///
+/// Must not be called from more than one place at a time.
+pub fn drain(mut f: impl FnMut(&Sample)) {
+    for ring in &RINGS {
+        let head = ring.head.load(Ordering::Acquire);
+        let mut tail = ring.tail.load(Ordering::Relaxed);
+        while tail != head {
+            let sample = unsafe { (*ring.samples.get())[tail % CAPACITY] };
+            tail = tail.wrapping_add(1);
+            ring.tail.store(tail, Ordering::Release);
+            f(&sample);
+        }
+    }
+}
+
+// Performance monitor registers, accessed through the external register interface
+#[cfg(XCHAL_HAVE_PROFILING)]
+const ERI_PERFMON_PGM: u32 = 0x10_1000;
+#[cfg(XCHAL_HAVE_PROFILING)]
+const ERI_PERFMON_PM0: u32 = 0x10_1080;
+#[cfg(XCHAL_HAVE_PROFILING)]
+const ERI_PERFMON_PMCTRL0: u32 = 0x10_1100;
+#[cfg(XCHAL_HAVE_PROFILING)]
+const ERI_PERFMON_PMSTAT0: u32 = 0x10_1180;
+
+/// PMCTRL: INTEN (interrupt on overflow), KRNLCNT, TRACELEVEL 15 (count at all levels),
+/// SELECT 0 and MASK 1 (count cycles)
+#[cfg(XCHAL_HAVE_PROFILING)]
+const PMCTRL_CYCLES: u32 = 0x0001_00f9;
+
+#[cfg(XCHAL_HAVE_PROFILING)]
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// Sample the current core every `period` cycles through the Profiling interrupt
+///
+/// Uses performance counter 0 of the current core.
+#[cfg(XCHAL_HAVE_PROFILING)]
+pub fn start(period: u32) {
+    PERIOD.store(period, Ordering::Relaxed);
+    unsafe {
+        wer(ERI_PERFMON_PGM, 0);
+        wer(ERI_PERFMON_PMCTRL0, PMCTRL_CYCLES);
+        wer(ERI_PERFMON_PMSTAT0, rer(ERI_PERFMON_PMSTAT0));
+        wer(ERI_PERFMON_PM0, 0u32.wrapping_sub(period));
+        crate::interrupt::modify_intenable(|enabled| enabled | (1 << PROFILING_INTERRUPT));
+        wer(ERI_PERFMON_PGM, 1);
+    }
+}
+
+/// Stop sampling the current core through the Profiling interrupt
+#[cfg(XCHAL_HAVE_PROFILING)]
+pub fn stop() {
+    unsafe {
+        wer(ERI_PERFMON_PGM, 0);
+        crate::interrupt::modify_intenable(|enabled| enabled & !(1 << PROFILING_INTERRUPT));
+    }
+}
+
+/// Profiling interrupt handler, unless the user provides `Profiling`
+#[cfg(XCHAL_HAVE_PROFILING)]
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_profiling_interrupt(_level: u32, save_frame: &Context) {
+    sample(save_frame);
+
+    // clear the overflow, which also clears the interrupt, and restart the period
+    unsafe {
+        wer(ERI_PERFMON_PMSTAT0, rer(ERI_PERFMON_PMSTAT0));
+        wer(
+            ERI_PERFMON_PM0,
+            0u32.wrapping_sub(PERIOD.load(Ordering::Relaxed)),
+        );
+    }
+}
+
+#[cfg(XCHAL_HAVE_PROFILING)]
+#[inline(always)]
+unsafe fn rer(address: u32) -> u32 {
+    let value: u32;
+    asm!("rer {0}, {1}", out(reg) value, in(reg) address, options(nostack));
+    value
+}
+
+#[cfg(XCHAL_HAVE_PROFILING)]
+#[inline(always)]
+unsafe fn wer(address: u32, value: u32) {
+    asm!("wer {0}, {1}", in(reg) value, in(reg) address, options(nostack));
+}
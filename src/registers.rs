@@ -0,0 +1,333 @@
+//! Typed views of special registers
+//!
+//! [`Ps`], [`DebugCause`], [`WindowBase`] and [`WindowStart`] decode the fields of the register
+//! values, which is what their `Debug` output shows, e.g. for the PS saved in a
+//! [`Context`](crate::exception::Context):
+//!
+//! ```text
+//! Ps { INTLEVEL: 0, EXCM: false, UM: true, RING: 0, OWB: 3, CALLINC: 2, WOE: true }
+//! ```
+//!
+//! The `read` and `write` functions access the registers of the current core. EXCCAUSE is decoded
+//! by [`ExceptionCause`](crate::exception::ExceptionCause).
+
+use core::arch::asm;
+use core::fmt;
+
+/// Processor state (PS)
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ps(pub u32);
+
+impl Ps {
+    const INTLEVEL: u32 = 0x0000_000f;
+    const EXCM: u32 = 0x0000_0010;
+    const UM: u32 = 0x0000_0020;
+    const WOE: u32 = 0x0004_0000;
+
+    /// Reads PS
+    #[inline]
+    pub fn read() -> Self {
+        let ps: u32;
+        unsafe { asm!("rsr.ps {0}", out(reg) ps, options(nostack)) };
+        Ps(ps)
+    }
+
+    /// Writes PS and waits for the change to take effect
+    ///
+    /// # Safety
+    ///
+    /// Changing the interrupt level, the exception mode or the window settings can break the
+    /// assumptions of the running code.
+    #[inline]
+    pub unsafe fn write(self) {
+        asm!("wsr.ps {0}", "rsync", in(reg) self.0, options(nostack));
+    }
+
+    /// The interrupt level, interrupts up to it are masked
+    pub fn intlevel(self) -> u32 {
+        self.0 & Self::INTLEVEL
+    }
+
+    /// Whether an exception is being handled, masking the interrupts up to `EXCM_LEVEL`
+    pub fn excm(self) -> bool {
+        self.0 & Self::EXCM != 0
+    }
+
+    /// User vector mode: exceptions go through the user vector rather than the kernel vector
+    pub fn um(self) -> bool {
+        self.0 & Self::UM != 0
+    }
+
+    /// The privilege level, 0 without MMU
+    pub fn ring(self) -> u32 {
+        (self.0 >> 6) & 0x3
+    }
+
+    /// The old window base, saved on window overflow and underflow exceptions
+    pub fn owb(self) -> u32 {
+        (self.0 >> 8) & 0xf
+    }
+
+    /// The window increment of the last `call4`, `call8` or `call12`, in units of 4 registers
+    pub fn callinc(self) -> u32 {
+        (self.0 >> 16) & 0x3
+    }
+
+    /// Whether window overflow exceptions are enabled
+    pub fn woe(self) -> bool {
+        self.0 & Self::WOE != 0
+    }
+
+    /// PS with the interrupt level set to `level`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is above 15.
+    pub fn with_intlevel(self, level: u32) -> Self {
+        assert!(level <= Self::INTLEVEL, "Invalid interrupt level {}", level);
+        Ps((self.0 & !Self::INTLEVEL) | level)
+    }
+
+    /// PS with EXCM set or cleared
+    pub fn with_excm(self, excm: bool) -> Self {
+        self.with(Self::EXCM, excm)
+    }
+
+    /// PS with UM set or cleared
+    pub fn with_um(self, um: bool) -> Self {
+        self.with(Self::UM, um)
+    }
+
+    /// PS with WOE set or cleared
+    pub fn with_woe(self, woe: bool) -> Self {
+        self.with(Self::WOE, woe)
+    }
+
+    fn with(self, mask: u32, set: bool) -> Self {
+        if set {
+            Ps(self.0 | mask)
+        } else {
+            Ps(self.0 & !mask)
+        }
+    }
+}
+
+impl From<u32> for Ps {
+    fn from(ps: u32) -> Self {
+        Ps(ps)
+    }
+}
+
+impl fmt::Debug for Ps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ps")
+            .field("INTLEVEL", &self.intlevel())
+            .field("EXCM", &self.excm())
+            .field("UM", &self.um())
+            .field("RING", &self.ring())
+            .field("OWB", &self.owb())
+            .field("CALLINC", &self.callinc())
+            .field("WOE", &self.woe())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ps {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "Ps {{ INTLEVEL: {=u32}, EXCM: {=bool}, UM: {=bool}, RING: {=u32}, OWB: {=u32}, CALLINC: {=u32}, WOE: {=bool} }}",
+            self.intlevel(),
+            self.excm(),
+            self.um(),
+            self.ring(),
+            self.owb(),
+            self.callinc(),
+            self.woe(),
+        )
+    }
+}
+
+/// Cause of the last debug exception (DEBUGCAUSE)
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct DebugCause(pub u32);
+
+impl DebugCause {
+    /// Reads DEBUGCAUSE, only meaningful in the debug exception handler
+    #[inline]
+    pub fn read() -> Self {
+        let cause: u32;
+        unsafe { asm!("rsr.debugcause {0}", out(reg) cause, options(nostack)) };
+        DebugCause(cause)
+    }
+
+    /// ICOUNT reached 0, i.e. a single step
+    pub fn icount(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    /// An instruction breakpoint (IBREAKA) matched
+    pub fn ibreak(self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    /// A data breakpoint (DBREAKA) matched, see [`dbnum`](Self::dbnum)
+    pub fn dbreak(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    /// A `break` instruction
+    pub fn break_instruction(self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    /// A `break.n` instruction
+    pub fn break_n_instruction(self) -> bool {
+        self.0 & 0x10 != 0
+    }
+
+    /// A debug interrupt, e.g. from JTAG or the other core
+    pub fn debug_interrupt(self) -> bool {
+        self.0 & 0x20 != 0
+    }
+
+    /// The data breakpoint that matched
+    pub fn dbnum(self) -> u32 {
+        (self.0 >> 8) & 0xf
+    }
+}
+
+impl From<u32> for DebugCause {
+    fn from(cause: u32) -> Self {
+        DebugCause(cause)
+    }
+}
+
+impl fmt::Debug for DebugCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugCause")
+            .field("ICOUNT", &self.icount())
+            .field("IBREAK", &self.ibreak())
+            .field("DBREAK", &self.dbreak())
+            .field("BI", &self.break_instruction())
+            .field("BN", &self.break_n_instruction())
+            .field("DEBUGINT", &self.debug_interrupt())
+            .field("DBNUM", &self.dbnum())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DebugCause {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "DebugCause {{ ICOUNT: {=bool}, IBREAK: {=bool}, DBREAK: {=bool}, BI: {=bool}, BN: {=bool}, DEBUGINT: {=bool}, DBNUM: {=u32} }}",
+            self.icount(),
+            self.ibreak(),
+            self.dbreak(),
+            self.break_instruction(),
+            self.break_n_instruction(),
+            self.debug_interrupt(),
+            self.dbnum(),
+        )
+    }
+}
+
+/// The current register window (WINDOWBASE), in units of 4 physical registers
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct WindowBase(pub u32);
+
+impl WindowBase {
+    /// Reads WINDOWBASE
+    #[cfg(XCHAL_HAVE_WINDOWED)]
+    #[inline]
+    pub fn read() -> Self {
+        let base: u32;
+        unsafe { asm!("rsr.windowbase {0}", out(reg) base, options(nostack)) };
+        WindowBase(base)
+    }
+
+    /// The index of the physical registers of A0-A3, divided by 4
+    pub fn base(self) -> u32 {
+        self.0 & 0xf
+    }
+}
+
+impl From<u32> for WindowBase {
+    fn from(base: u32) -> Self {
+        WindowBase(base)
+    }
+}
+
+impl fmt::Debug for WindowBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WindowBase").field(&self.base()).finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for WindowBase {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "WindowBase({=u32})", self.base())
+    }
+}
+
+/// The register windows holding live registers (WINDOWSTART), one bit per 4 physical registers
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct WindowStart(pub u32);
+
+impl WindowStart {
+    /// Reads WINDOWSTART
+    #[cfg(XCHAL_HAVE_WINDOWED)]
+    #[inline]
+    pub fn read() -> Self {
+        let start: u32;
+        unsafe { asm!("rsr.windowstart {0}", out(reg) start, options(nostack)) };
+        WindowStart(start)
+    }
+
+    /// Whether a frame starts at the physical registers `4 * n`
+    pub fn is_start(self, n: u32) -> bool {
+        n < 16 && self.0 & (1 << n) != 0
+    }
+}
+
+impl From<u32> for WindowStart {
+    fn from(start: u32) -> Self {
+        WindowStart(start)
+    }
+}
+
+impl fmt::Debug for WindowStart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WindowStart({:#018b})", self.0 & 0xffff)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for WindowStart {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "WindowStart({=u32:#b})", self.0 & 0xffff)
+    }
+}
+
+/// Reads the processor ID (PRID)
+#[cfg(XCHAL_HAVE_PRID)]
+#[inline]
+pub fn prid() -> u32 {
+    let prid: u32;
+    unsafe { asm!("rsr.prid {0}", out(reg) prid, options(nostack)) };
+    prid
+}
+
+/// Moves the exception and interrupt vectors to `base` (VECBASE)
+///
+/// # Safety
+///
+/// `base` must point to vectors laid out like the ones of the linker script.
+#[inline]
+pub unsafe fn set_vecbase(base: *const u32) {
+    asm!("wsr.vecbase {0}", in(reg) base, options(nostack));
+}
@@ -0,0 +1,390 @@
+//! Semihosting
+//!
+//! Host calls with the Xtensa semihosting convention: `break 1, 14` with the operation in A2 and
+//! the argument, usually the address of a parameter block, in A3. The result is returned in A2.
+//! The operations are the ones of ARM semihosting, which OpenOCD and QEMU implement for Xtensa:
+//!
+//! ```ignore
+//! use xtensa_lx_rt::{hprintln, semihosting};
+//!
+//! hprintln!("running {} tests", tests.len());
+//! let status = if failed == 0 {
+//!     semihosting::EXIT_SUCCESS
+//! } else {
+//!     semihosting::EXIT_FAILURE
+//! };
+//! semihosting::exit(status);
+//! ```
+//!
+//! Without debugger or emulator the `break` raises the debug exception. The runtime then
+//! completes the call as failed, -1 in A2, and continues after it, so printing is a no-op and
+//! [`exit`] halts the core.
+
+use core::arch::asm;
+use core::ffi::CStr;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::exception::Context;
+use crate::registers::DebugCause;
+
+/// Operation numbers
+pub mod nr {
+    pub const OPEN: u32 = 0x01;
+    pub const CLOSE: u32 = 0x02;
+    pub const WRITEC: u32 = 0x03;
+    pub const WRITE0: u32 = 0x04;
+    pub const WRITE: u32 = 0x05;
+    pub const READ: u32 = 0x06;
+    pub const READC: u32 = 0x07;
+    pub const ISERROR: u32 = 0x08;
+    pub const ISTTY: u32 = 0x09;
+    pub const SEEK: u32 = 0x0a;
+    pub const FLEN: u32 = 0x0c;
+    pub const TMPNAM: u32 = 0x0d;
+    pub const REMOVE: u32 = 0x0e;
+    pub const RENAME: u32 = 0x0f;
+    pub const CLOCK: u32 = 0x10;
+    pub const TIME: u32 = 0x11;
+    pub const SYSTEM: u32 = 0x12;
+    pub const ERRNO: u32 = 0x13;
+    pub const GET_CMDLINE: u32 = 0x15;
+    pub const HEAPINFO: u32 = 0x16;
+    pub const EXIT: u32 = 0x18;
+    pub const EXIT_EXTENDED: u32 = 0x20;
+    pub const ELAPSED: u32 = 0x30;
+    pub const TICKFREQ: u32 = 0x31;
+}
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+const ADP_STOPPED_RUN_TIME_ERROR_UNKNOWN: u32 = 0x2_0023;
+
+/// Returned by failed calls
+const FAILED: u32 = u32::MAX;
+
+/// Perform the host call `op`
+///
+/// # Safety
+///
+/// `arg` must be valid for the operation, e.g. point to a parameter block of the right size.
+#[inline]
+pub unsafe fn syscall(op: u32, arg: u32) -> u32 {
+    __xtensa_lx_rt_semihosting(op, arg)
+}
+
+/// The windowed ABI passes the arguments in A2 and A3 and the result in A2, just like the host
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn __xtensa_lx_rt_semihosting(_op: u32, _arg: u32) -> u32 {
+    asm!(
+        "
+        entry   a1, 16
+        break   1, 14
+        retw
+        ",
+        options(noreturn)
+    );
+}
+
+/// A failed host call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    /// The host's `errno` after the call
+    pub errno: u32,
+}
+
+fn last_error() -> Error {
+    Error {
+        errno: unsafe { syscall(nr::ERRNO, 0) },
+    }
+}
+
+/// How a file is opened, like the `fopen` mode strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum OpenMode {
+    /// `r`
+    Read = 0,
+    /// `rb`
+    ReadBinary = 1,
+    /// `r+`
+    ReadWrite = 2,
+    /// `r+b`
+    ReadWriteBinary = 3,
+    /// `w`
+    Write = 4,
+    /// `wb`
+    WriteBinary = 5,
+    /// `w+`
+    WriteRead = 6,
+    /// `w+b`
+    WriteReadBinary = 7,
+    /// `a`
+    Append = 8,
+    /// `ab`
+    AppendBinary = 9,
+    /// `a+`
+    AppendRead = 10,
+    /// `a+b`
+    AppendReadBinary = 11,
+}
+
+/// A file on the host
+#[derive(Debug)]
+pub struct File {
+    handle: u32,
+}
+
+impl File {
+    /// Open `path` on the host, `:tt` is the host's console
+    pub fn open(path: &CStr, mode: OpenMode) -> Result<File, Error> {
+        let path = path.to_bytes();
+        let block = [path.as_ptr() as u32, mode as u32, path.len() as u32];
+        match unsafe { syscall(nr::OPEN, block.as_ptr() as u32) } {
+            FAILED => Err(last_error()),
+            handle => Ok(File { handle }),
+        }
+    }
+
+    /// The host's handle of the file
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    /// Write from `buffer`, returns the number of bytes written
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        let block = [self.handle, buffer.as_ptr() as u32, buffer.len() as u32];
+        // the host returns the number of bytes not written
+        match unsafe { syscall(nr::WRITE, block.as_ptr() as u32) } as usize {
+            left if left <= buffer.len() => Ok(buffer.len() - left),
+            _ => Err(last_error()),
+        }
+    }
+
+    /// Write all of `buffer`
+    pub fn write_all(&mut self, mut buffer: &[u8]) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            match self.write(buffer)? {
+                0 => return Err(last_error()),
+                written => buffer = &buffer[written..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Read into `buffer`, returns the number of bytes read, 0 at the end of the file
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let block = [self.handle, buffer.as_mut_ptr() as u32, buffer.len() as u32];
+        // the host returns the number of bytes not read
+        match unsafe { syscall(nr::READ, block.as_ptr() as u32) } as usize {
+            left if left <= buffer.len() => Ok(buffer.len() - left),
+            _ => Err(last_error()),
+        }
+    }
+
+    /// Move to `position` bytes from the start of the file
+    pub fn seek(&mut self, position: u32) -> Result<(), Error> {
+        let block = [self.handle, position];
+        match unsafe { syscall(nr::SEEK, block.as_ptr() as u32) } {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+
+    /// The length of the file in bytes
+    pub fn size(&self) -> Result<u32, Error> {
+        let block = [self.handle];
+        match unsafe { syscall(nr::FLEN, block.as_ptr() as u32) } {
+            FAILED => Err(last_error()),
+            size => Ok(size),
+        }
+    }
+
+    /// Close the file
+    pub fn close(self) -> Result<(), Error> {
+        let block = [self.handle];
+        match unsafe { syscall(nr::CLOSE, block.as_ptr() as u32) } {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+}
+
+impl fmt::Write for File {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+const NOT_OPENED: u32 = u32::MAX;
+
+static STDOUT: AtomicU32 = AtomicU32::new(NOT_OPENED);
+static STDERR: AtomicU32 = AtomicU32::new(NOT_OPENED);
+
+/// The host's standard output, opened on first use and never closed
+#[derive(Debug)]
+pub struct HostStream(File);
+
+impl HostStream {
+    /// Write all of `buffer`
+    pub fn write_all(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.0.write_all(buffer)
+    }
+}
+
+impl fmt::Write for HostStream {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+/// The host's standard output
+pub fn hstdout() -> Result<HostStream, Error> {
+    console(&STDOUT, OpenMode::Write)
+}
+
+/// The host's standard error
+pub fn hstderr() -> Result<HostStream, Error> {
+    console(&STDERR, OpenMode::Append)
+}
+
+fn console(handle: &AtomicU32, mode: OpenMode) -> Result<HostStream, Error> {
+    match handle.load(Ordering::Relaxed) {
+        NOT_OPENED => {
+            let path = CStr::from_bytes_with_nul(b":tt\0").unwrap();
+            let file = File::open(path, mode)?;
+            handle.store(file.handle, Ordering::Relaxed);
+            Ok(HostStream(file))
+        }
+        handle => Ok(HostStream(File { handle })),
+    }
+}
+
+#[doc(hidden)]
+pub fn print(args: fmt::Arguments, stderr: bool) {
+    let stream = if stderr { hstderr() } else { hstdout() };
+    if let Ok(mut stream) = stream {
+        let _ = fmt::Write::write_fmt(&mut stream, args);
+    }
+}
+
+/// Print to the host's standard output
+#[macro_export]
+macro_rules! hprint {
+    ($($arg:tt)*) => {
+        $crate::semihosting::print(format_args!($($arg)*), false)
+    };
+}
+
+/// Print to the host's standard output, with a newline
+#[macro_export]
+macro_rules! hprintln {
+    () => {
+        $crate::semihosting::print(format_args!("\n"), false)
+    };
+    ($($arg:tt)*) => {
+        $crate::semihosting::print(format_args!("{}\n", format_args!($($arg)*)), false)
+    };
+}
+
+/// Print to the host's standard error
+#[macro_export]
+macro_rules! heprint {
+    ($($arg:tt)*) => {
+        $crate::semihosting::print(format_args!($($arg)*), true)
+    };
+}
+
+/// Print to the host's standard error, with a newline
+#[macro_export]
+macro_rules! heprintln {
+    () => {
+        $crate::semihosting::print(format_args!("\n"), true)
+    };
+    ($($arg:tt)*) => {
+        $crate::semihosting::print(format_args!("{}\n", format_args!($($arg)*)), true)
+    };
+}
+
+/// End the session with the exit `code`, e.g. to report the result of tests run in an emulator
+pub fn exit(code: i32) -> ! {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code as u32];
+    unsafe { syscall(nr::EXIT_EXTENDED, block.as_ptr() as u32) };
+
+    // hosts without EXIT_EXTENDED only tell success from failure
+    let reason = match code {
+        EXIT_SUCCESS => ADP_STOPPED_APPLICATION_EXIT,
+        _ => ADP_STOPPED_RUN_TIME_ERROR_UNKNOWN,
+    };
+    unsafe { syscall(nr::EXIT, reason) };
+
+    loop {
+        unsafe { asm!("waiti 15", options(nostack)) };
+    }
+}
+
+/// Seconds since 1970-01-01 on the host
+pub fn time() -> Result<u32, Error> {
+    match unsafe { syscall(nr::TIME, 0) } {
+        FAILED => Err(last_error()),
+        time => Ok(time),
+    }
+}
+
+/// Centiseconds since the start of the session
+pub fn clock() -> Result<u32, Error> {
+    match unsafe { syscall(nr::CLOCK, 0) } {
+        FAILED => Err(last_error()),
+        clock => Ok(clock),
+    }
+}
+
+/// Read the command line the host started the program with into `buffer`
+pub fn command_line(buffer: &mut [u8]) -> Result<&[u8], Error> {
+    let mut block = [buffer.as_mut_ptr() as u32, buffer.len() as u32];
+    match unsafe { syscall(nr::GET_CMDLINE, block.as_mut_ptr() as u32) } {
+        // the host updates the length, without the terminating NUL
+        0 => Ok(&buffer[..(block[1] as usize).min(buffer.len())]),
+        _ => Err(last_error()),
+    }
+}
+
+/// The space separated arguments of the [`command_line`], the program name first
+pub fn args(buffer: &mut [u8]) -> Result<impl Iterator<Item = &[u8]>, Error> {
+    let command_line = command_line(buffer)?;
+    Ok(command_line
+        .split(|&c| c == b' ')
+        .filter(|arg| !arg.is_empty()))
+}
+
+/// Encoding of `break 1, 14`
+const BREAK_1_14: u32 = 0x00_41e0;
+
+/// Complete a host call no host handled, the debug exception was taken instead
+///
+/// Returns whether the exception was a host call.
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_semihosting_break(save_frame: &mut Context) -> u32 {
+    if !DebugCause::read().break_instruction() || instruction(save_frame.PC) != BREAK_1_14 {
+        return 0;
+    }
+
+    save_frame.A2 = FAILED;
+    save_frame.PC += 3;
+    1
+}
+
+/// The 24 bit instruction at `pc`
+fn instruction(pc: u32) -> u32 {
+    // instruction RAM only supports aligned word accesses
+    let address = (pc & !3) as *const u32;
+    let words = unsafe {
+        (address.read_volatile() as u64) | ((address.add(1).read_volatile() as u64) << 32)
+    };
+    (words >> ((pc & 3) * 8)) as u32 & 0x00ff_ffff
+}
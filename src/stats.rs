@@ -0,0 +1,121 @@
+//! Interrupt and exception handler statistics
+//!
+//! With the `stats` feature the runtime counts the calls of the level 1-7 interrupt handlers and
+//! of the exception handler per cause, and measures the CCOUNT cycles spent in them. Saving and
+//! restoring the context is not included. Handlers defined with `#[interrupt(n, fast)]` are not
+//! measured.
+//!
+//! The numbers are kept per core, [`snapshot`] returns the ones of the current core.
+
+use core::arch::asm;
+
+use crate::exception::ExceptionCause;
+use crate::interrupt::free;
+
+/// Calls of one handler and the cycles spent in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HandlerStats {
+    pub count: u32,
+    pub total_cycles: u64,
+    pub max_cycles: u32,
+}
+
+impl HandlerStats {
+    const NEW: HandlerStats = HandlerStats {
+        count: 0,
+        total_cycles: 0,
+        max_cycles: 0,
+    };
+
+    fn record(&mut self, cycles: u32) {
+        self.count = self.count.wrapping_add(1);
+        self.total_cycles = self.total_cycles.wrapping_add(cycles as u64);
+        self.max_cycles = self.max_cycles.max(cycles);
+    }
+}
+
+/// The statistics of one core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Handlers of the interrupt levels 1 to 7
+    pub levels: [HandlerStats; 7],
+    /// Exception handler calls, indexed by [`ExceptionCause`]
+    pub exceptions: [HandlerStats; 64],
+    /// Maximum number of nested handlers
+    pub max_nesting: u32,
+}
+
+impl Stats {
+    const NEW: Stats = Stats {
+        levels: [HandlerStats::NEW; 7],
+        exceptions: [HandlerStats::NEW; 64],
+        max_nesting: 0,
+    };
+
+    /// Statistics of the interrupt handler of `level` (1 to 7)
+    pub fn level(&self, level: u32) -> Option<&HandlerStats> {
+        self.levels.get((level as usize).checked_sub(1)?)
+    }
+
+    /// Statistics of the exception handler for `cause`, `None` for `ExceptionCause::None`
+    pub fn exception(&self, cause: ExceptionCause) -> Option<&HandlerStats> {
+        self.exceptions.get(cause as usize)
+    }
+}
+
+static mut STATS: [Stats; 2] = [Stats::NEW; 2];
+static mut NESTING: [u32; 2] = [0; 2];
+
+/// The statistics of the current core
+pub fn snapshot() -> Stats {
+    free(|| unsafe { STATS[crate::core_id()] })
+}
+
+/// Clear the statistics of the current core
+pub fn reset() {
+    free(|| unsafe { STATS[crate::core_id()] = Stats::NEW });
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_stats_enter() {
+    free(|| unsafe {
+        let core = crate::core_id();
+        NESTING[core] += 1;
+        STATS[core].max_nesting = STATS[core].max_nesting.max(NESTING[core]);
+    });
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_stats_interrupt_exit(level: u32, start: u32) {
+    let cycles = ccount().wrapping_sub(start);
+    free(|| unsafe {
+        let core = crate::core_id();
+        NESTING[core] -= 1;
+        STATS[core].levels[level as usize - 1].record(cycles);
+    });
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __xtensa_lx_rt_stats_exception_exit(cause: u32, start: u32) {
+    let cycles = ccount().wrapping_sub(start);
+    free(|| unsafe {
+        let core = crate::core_id();
+        NESTING[core] -= 1;
+        STATS[core].exceptions[cause as usize & 63].record(cycles);
+    });
+}
+
+#[inline(always)]
+fn ccount() -> u32 {
+    let ccount: u32;
+    unsafe { asm!("rsr.ccount {0}", out(reg) ccount, options(nostack)) };
+    ccount
+}
@@ -0,0 +1,106 @@
+//! System calls
+//!
+//! The `syscall` instruction raises the `Syscall` [exception](crate::exception::ExceptionCause),
+//! which the runtime dispatches to the handler defined with `#[syscall(n)]` for the number in A2:
+//!
+//! ```ignore
+//! #[syscall(1)]
+//! fn write(args: [u32; 5]) -> u32 {
+//!     // args are the caller's A3-A7
+//!     0
+//! }
+//!
+//! let written = xtensa_lx_rt::syscall::syscall(1, [buffer as u32, len, 0, 0, 0]);
+//! ```
+//!
+//! The return value is passed back in A2 and execution continues after the `syscall`
+//! instruction. Numbers without handler return [`UNKNOWN`]. Handlers run like exception handlers,
+//! with interrupts up to `PS_INTLEVEL_EXCM` masked.
+//!
+//! [`enter_user_mode`] sets PS.UM, so the exceptions of the application are taken through the user
+//! vector while the runtime's handlers run with PS.UM cleared. Without memory protection this
+//! only separates the two exception paths, it doesn't restrict what user mode code can access.
+
+use core::arch::asm;
+
+use crate::exception::Context;
+use crate::registers::Ps;
+
+/// Returned for syscall numbers without handler
+pub const UNKNOWN: u32 = u32::MAX;
+
+/// An entry of the handler table, emitted by `#[syscall(n)]`
+#[doc(hidden)]
+#[repr(C)]
+pub struct SyscallEntry {
+    pub number: u32,
+    pub handler: fn([u32; 5]) -> u32,
+}
+
+extern "C" {
+    static __syscalls_start: u32;
+    static __syscalls_end: u32;
+}
+
+/// Make the exceptions of the calling code go through the user vector
+///
+/// # Safety
+///
+/// Must not be called from an interrupt or exception handler.
+pub unsafe fn enter_user_mode() {
+    Ps::read().with_um(true).write();
+}
+
+/// Call the `#[syscall(number)]` handler with `args`
+#[inline]
+pub fn syscall(number: u32, args: [u32; 5]) -> u32 {
+    unsafe { __xtensa_lx_rt_syscall(number, args[0], args[1], args[2], args[3], args[4]) }
+}
+
+/// The windowed ABI passes the arguments in A2-A7 and the result in A2, just like `syscall`
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn __xtensa_lx_rt_syscall(
+    _number: u32,
+    _a3: u32,
+    _a4: u32,
+    _a5: u32,
+    _a6: u32,
+    _a7: u32,
+) -> u32 {
+    asm!(
+        "
+        entry   a1, 16
+        syscall
+        retw
+        ",
+        options(noreturn)
+    );
+}
+
+/// Run the handler of the syscall raised in `save_frame` and return to the next instruction
+pub(crate) fn dispatch(save_frame: &mut Context) {
+    let handlers = unsafe {
+        let start = &__syscalls_start as *const u32 as *const SyscallEntry;
+        let end = &__syscalls_end as *const u32 as *const SyscallEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    save_frame.A2 = match handlers.iter().find(|entry| entry.number == save_frame.A2) {
+        Some(entry) => (entry.handler)([
+            save_frame.A3,
+            save_frame.A4,
+            save_frame.A5,
+            save_frame.A6,
+            save_frame.A7,
+        ]),
+        None => UNKNOWN,
+    };
+
+    save_frame.PC += 3;
+    // a `syscall` ending a zero overhead loop has to continue with the next iteration
+    #[cfg(XCHAL_HAVE_LOOPS)]
+    if save_frame.PC == save_frame.LEND && save_frame.LCOUNT != 0 {
+        save_frame.LCOUNT -= 1;
+        save_frame.PC = save_frame.LBEG;
+    }
+}
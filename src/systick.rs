@@ -0,0 +1,366 @@
+//! System tick and alarm queue driven by the CCOUNT/CCOMPARE timers
+//!
+//! [`start`] turns one of the CCOMPARE timers into the time base of the current core. The timer
+//! interrupt is programmed for the earliest pending alarm, or at least every 2^31 cycles so that
+//! the 64-bit extension of CCOUNT behind [`Instant`] doesn't miss a wrap.
+//!
+//! The runtime doesn't dispatch the timer interrupt itself: the handler of its interrupt level has
+//! to call [`on_interrupt`], for example with `Timer0` (level 1):
+//!
+//! ```ignore
+//! #[interrupt(1)]
+//! fn level_1_interrupt() {
+//!     xtensa_lx_rt::systick::on_interrupt();
+//! }
+//! ```
+//!
+//! Alarm callbacks are called from [`on_interrupt`], at the interrupt level of the timer.
+//!
+//! Every core has its own system tick and alarm queue: the functions below act on the ones of the
+//! core they are called on, so alarms fire on the core that scheduled them once it has called
+//! [`start`], and their deadlines are instants of that core. The state of a core is only touched
+//! by that core, with its interrupts disabled.
+//!
+//! With the `fugit` feature, [`Monotonic`] drives a CCOMPARE timer the same way for a scheduler
+//! that keeps its own queue, following the `rtic_monotonic::Monotonic` interface.
+
+use core::arch::asm;
+
+use crate::interrupt::{free, modify_intenable};
+use crate::time::{Duration, Instant};
+
+/// The CCOMPARE timers of the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    #[cfg(XCHAL_HAVE_TIMER0)]
+    Timer0,
+    #[cfg(XCHAL_HAVE_TIMER1)]
+    Timer1,
+    #[cfg(XCHAL_HAVE_TIMER2)]
+    Timer2,
+    #[cfg(XCHAL_HAVE_TIMER3)]
+    Timer3,
+}
+
+include!(concat!(env!("OUT_DIR"), "/timer_interrupts.rs"));
+
+/// Maximum number of pending alarms
+pub const MAX_ALARMS: usize = 16;
+
+/// Longest time between two timer interrupts in cycles, so that every CCOUNT wrap is seen
+const MAX_INTERVAL: u64 = 1 << 31;
+
+/// Shortest time until the next timer interrupt in cycles, so that CCOUNT hasn't passed CCOMPARE
+/// already by the time it is written
+const MIN_INTERVAL: u64 = 256;
+
+/// Identifies a scheduled alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmId(u32);
+
+/// All [`MAX_ALARMS`] alarms are pending already
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    deadline: Instant,
+    /// zero for one-shot alarms
+    period: Duration,
+    id: AlarmId,
+    callback: fn(AlarmId),
+}
+
+fn no_callback(_: AlarmId) {}
+
+const NO_ALARM: Alarm = Alarm {
+    deadline: Instant::from_ticks(0),
+    period: Duration::ZERO,
+    id: AlarmId(0),
+    callback: no_callback,
+};
+
+/// Pending alarms, sorted by deadline
+struct Queue {
+    alarms: [Alarm; MAX_ALARMS],
+    len: usize,
+    next_id: u32,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            alarms: [NO_ALARM; MAX_ALARMS],
+            len: 0,
+            next_id: 0,
+        }
+    }
+
+    fn first(&self) -> Option<&Alarm> {
+        self.alarms[..self.len].first()
+    }
+
+    fn insert(&mut self, alarm: Alarm) -> Result<(), QueueFull> {
+        if self.len == MAX_ALARMS {
+            return Err(QueueFull);
+        }
+
+        // after all alarms with the same deadline, so they fire in the order they were scheduled
+        let pos = self.alarms[..self.len]
+            .iter()
+            .position(|a| a.deadline > alarm.deadline)
+            .unwrap_or(self.len);
+        self.alarms.copy_within(pos..self.len, pos + 1);
+        self.alarms[pos] = alarm;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, pos: usize) -> Alarm {
+        let alarm = self.alarms[pos];
+        self.alarms.copy_within(pos + 1..self.len, pos);
+        self.len -= 1;
+        alarm
+    }
+}
+
+static mut TIMERS: [Option<Timer>; 2] = [None; 2];
+static mut QUEUES: [Queue; 2] = [Queue::new(), Queue::new()];
+
+/// The alarm queue of the current core, must be used with interrupts disabled
+unsafe fn queue() -> &'static mut Queue {
+    &mut QUEUES[crate::core_id()]
+}
+
+/// Use `timer` as the system tick of the current core and enable its interrupt
+///
+/// # Safety
+///
+/// The handler of the interrupt level of `timer` must call [`on_interrupt`] and `timer` must not
+/// be used for anything else until [`stop`] is called.
+pub unsafe fn start(timer: Timer) {
+    free(|| {
+        TIMERS[crate::core_id()] = Some(timer);
+        program();
+    });
+    modify_intenable(|enabled| enabled | (1 << timer.interrupt()));
+}
+
+/// Disable the interrupt of the system tick of the current core, pending alarms are kept but don't
+/// fire anymore
+pub fn stop() {
+    if let Some(timer) = free(|| unsafe { TIMERS[crate::core_id()].take() }) {
+        unsafe { modify_intenable(|enabled| enabled & !(1 << timer.interrupt())) };
+    }
+}
+
+/// Call `callback` once `deadline` is reached
+pub fn alarm_at(deadline: Instant, callback: fn(AlarmId)) -> Result<AlarmId, QueueFull> {
+    schedule(deadline, Duration::ZERO, callback)
+}
+
+/// Call `callback` once, `delay` from now
+pub fn alarm_after(delay: Duration, callback: fn(AlarmId)) -> Result<AlarmId, QueueFull> {
+    schedule(after(delay), Duration::ZERO, callback)
+}
+
+/// Call `callback` every `period`, starting `period` from now
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn periodic(period: Duration, callback: fn(AlarmId)) -> Result<AlarmId, QueueFull> {
+    assert!(
+        period != Duration::ZERO,
+        "the period of an alarm must not be zero"
+    );
+    schedule(after(period), period, callback)
+}
+
+/// Remove a pending alarm, returns `false` if it already fired (one-shot) or was cancelled
+///
+/// Must be called on the core that scheduled the alarm.
+pub fn cancel(id: AlarmId) -> bool {
+    free(|| unsafe {
+        let queue = queue();
+        match queue.alarms[..queue.len].iter().position(|a| a.id == id) {
+            Some(pos) => {
+                queue.remove(pos);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Call the callbacks of all expired alarms and program the next timer interrupt
+///
+/// Must be called by the handler of the interrupt level of the timer passed to [`start`], this
+/// also clears the timer interrupt.
+pub fn on_interrupt() {
+    loop {
+        let due = free(|| unsafe {
+            let queue = queue();
+            let now = Instant::now();
+            match queue.first() {
+                Some(alarm) if alarm.deadline <= now => {
+                    let alarm = queue.remove(0);
+                    if alarm.period != Duration::ZERO {
+                        // keep the phase, unless whole periods were missed
+                        let mut deadline = saturating_add(alarm.deadline, alarm.period);
+                        if deadline <= now {
+                            deadline = saturating_add(now, alarm.period);
+                        }
+                        // the slot was just freed, this can't fail
+                        let _ = queue.insert(Alarm { deadline, ..alarm });
+                    }
+                    Some(alarm)
+                }
+                _ => None,
+            }
+        });
+
+        match due {
+            // the lock is released, so the callback can schedule and cancel alarms
+            Some(alarm) => (alarm.callback)(alarm.id),
+            None => break,
+        }
+    }
+
+    free(|| unsafe { program() });
+}
+
+fn after(delay: Duration) -> Instant {
+    saturating_add(Instant::now(), delay)
+}
+
+/// `instant + duration`, or the last representable instant, which is never reached
+fn saturating_add(instant: Instant, duration: Duration) -> Instant {
+    instant
+        .checked_add(duration)
+        .unwrap_or(Instant::from_ticks(u64::MAX))
+}
+
+fn schedule(
+    deadline: Instant,
+    period: Duration,
+    callback: fn(AlarmId),
+) -> Result<AlarmId, QueueFull> {
+    free(|| unsafe {
+        let queue = queue();
+        let id = AlarmId(queue.next_id);
+        queue.insert(Alarm {
+            deadline,
+            period,
+            id,
+            callback,
+        })?;
+        queue.next_id = queue.next_id.wrapping_add(1);
+
+        if queue.first().map(|a| a.id) == Some(id) {
+            program();
+        }
+        Ok(id)
+    })
+}
+
+/// Set CCOMPARE for the earliest alarm of the current core, must be called with interrupts
+/// disabled
+unsafe fn program() {
+    let timer = match TIMERS[crate::core_id()] {
+        Some(timer) => timer,
+        None => return,
+    };
+
+    let now = Instant::now();
+    let interval = queue()
+        .first()
+        .map_or(Duration::MAX, |a| a.deadline.duration_since(now))
+        .ticks()
+        .clamp(MIN_INTERVAL, MAX_INTERVAL);
+    set_ccompare(timer, now.ticks().wrapping_add(interval) as u32);
+}
+
+/// A monotonic clock on a CCOMPARE timer, in the style of `rtic_monotonic::Monotonic`
+///
+/// Its instants and durations count the cycles of the current core, `CPU_HZ` has to be the CPU
+/// frequency. The compare interrupt is programmed at least every 2^31 cycles, like the system
+/// tick's, so the handler of the timer's interrupt level must call [`clear_compare_flag`] and
+/// [`set_compare`] (the scheduler does) even when no deadline is due.
+///
+/// [`clear_compare_flag`]: Monotonic::clear_compare_flag
+/// [`set_compare`]: Monotonic::set_compare
+#[cfg(feature = "fugit")]
+pub struct Monotonic<const CPU_HZ: u32> {
+    timer: Timer,
+}
+
+#[cfg(feature = "fugit")]
+impl<const CPU_HZ: u32> Monotonic<CPU_HZ> {
+    /// Use `timer` as monotonic clock of the current core
+    ///
+    /// # Safety
+    ///
+    /// `timer` must not be passed to [`start`] or used for anything else while the monotonic
+    /// exists, and the monotonic must only be used on the current core.
+    pub unsafe fn new(timer: Timer) -> Self {
+        Monotonic { timer }
+    }
+
+    /// The current instant of the cycle counter
+    pub fn now(&mut self) -> fugit::TimerInstantU64<CPU_HZ> {
+        fugit::TimerInstantU64::from_ticks(Instant::now().ticks())
+    }
+
+    /// The instant the cycle counter started from
+    pub fn zero() -> fugit::TimerInstantU64<CPU_HZ> {
+        fugit::TimerInstantU64::from_ticks(0)
+    }
+
+    /// Request the timer interrupt at `instant`, or earlier if it is more than 2^31 cycles away
+    pub fn set_compare(&mut self, instant: fugit::TimerInstantU64<CPU_HZ>) {
+        free(|| unsafe {
+            let now = Instant::now();
+            let interval = instant
+                .ticks()
+                .saturating_sub(now.ticks())
+                .clamp(MIN_INTERVAL, MAX_INTERVAL);
+            set_ccompare(self.timer, now.ticks().wrapping_add(interval) as u32);
+        });
+    }
+
+    /// Clear the pending timer interrupt
+    ///
+    /// The next interrupt is 2^31 cycles away until [`set_compare`](Self::set_compare) is called.
+    pub fn clear_compare_flag(&mut self) {
+        free(|| unsafe {
+            let now = Instant::now();
+            set_ccompare(self.timer, now.ticks().wrapping_add(MAX_INTERVAL) as u32);
+        });
+    }
+
+    /// Enable the timer interrupt
+    pub fn enable_timer(&mut self) {
+        unsafe { modify_intenable(|enabled| enabled | (1 << self.timer.interrupt())) };
+    }
+
+    /// Disable the timer interrupt
+    pub fn disable_timer(&mut self) {
+        unsafe { modify_intenable(|enabled| enabled & !(1 << self.timer.interrupt())) };
+    }
+}
+
+/// Writing CCOMPARE also clears a pending interrupt of the timer
+#[inline(always)]
+unsafe fn set_ccompare(timer: Timer, value: u32) {
+    match timer {
+        #[cfg(XCHAL_HAVE_TIMER0)]
+        Timer::Timer0 => asm!("wsr.ccompare0 {0}", "isync", in(reg) value, options(nostack)),
+        #[cfg(XCHAL_HAVE_TIMER1)]
+        Timer::Timer1 => asm!("wsr.ccompare1 {0}", "isync", in(reg) value, options(nostack)),
+        #[cfg(XCHAL_HAVE_TIMER2)]
+        Timer::Timer2 => asm!("wsr.ccompare2 {0}", "isync", in(reg) value, options(nostack)),
+        #[cfg(XCHAL_HAVE_TIMER3)]
+        Timer::Timer3 => asm!("wsr.ccompare3 {0}", "isync", in(reg) value, options(nostack)),
+    }
+}
@@ -0,0 +1,318 @@
+//! Monotonic time based on the CCOUNT cycle counter
+//!
+//! The 32-bit CCOUNT register wraps every few seconds, [`Instant`] extends it to 64 bits. The
+//! extension is updated with all maskable interrupts disabled, so it stays consistent when
+//! [`Instant::now`] is called from handlers of any interrupt level. It relies on CCOUNT being read
+//! at least once per wrap, which the [`systick`](crate::systick) guarantees when it's running.
+//!
+//! Every core has its own CCOUNT, so instants of different cores can't be compared.
+//!
+//! [`Instant`] and [`Duration`] count CPU cycles, the conversions from and to seconds use the
+//! frequency passed to [`set_cpu_frequency`] at startup.
+//!
+//! With the `embedded-hal` feature [`Delay`] implements `embedded_hal::delay::DelayNs`, with the
+//! `fugit` feature both types convert from and to their `fugit` counterparts and
+//! `systick::Monotonic` offers the cycle counter as monotonic clock for schedulers.
+
+use core::arch::asm;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::interrupt::free;
+
+static CPU_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+struct Counter {
+    last: u32,
+    high: u32,
+}
+
+static mut COUNTERS: [Counter; 2] = [Counter { last: 0, high: 0 }; 2];
+
+/// Set the CPU frequency in Hz used to convert between cycles and seconds
+///
+/// Must be called once at startup, before any of the conversions are used.
+pub fn set_cpu_frequency(hz: u32) {
+    CPU_FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// The CPU frequency in Hz passed to [`set_cpu_frequency`]
+///
+/// # Panics
+///
+/// Panics if [`set_cpu_frequency`] wasn't called yet.
+pub fn cpu_frequency() -> u32 {
+    match CPU_FREQUENCY.load(Ordering::Relaxed) {
+        0 => panic!("time::set_cpu_frequency must be called first"),
+        hz => hz,
+    }
+}
+
+/// A point in time of the 64-bit cycle counter of the current core
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current value of the cycle counter
+    pub fn now() -> Self {
+        Instant(free(|| unsafe { extend(ccount()) }))
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    /// CPU cycles since CCOUNT was 0 for the first time
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time passed since `self`
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time passed between `earlier` and `self`, zero if `earlier` is later
+    pub const fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub const fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        match self.0.checked_add(duration.0) {
+            Some(ticks) => Some(Instant(ticks)),
+            None => None,
+        }
+    }
+
+    pub const fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        match self.0.checked_sub(duration.0) {
+            Some(ticks) => Some(Instant(ticks)),
+            None => None,
+        }
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        Duration(self.0 - rhs.0)
+    }
+}
+
+/// A span of time in CPU cycles
+///
+/// Conversions from seconds round up, so delays are never shorter than requested. Conversions to
+/// seconds round down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+    pub const MAX: Duration = Duration(u64::MAX);
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Duration(ticks)
+    }
+
+    /// Number of CPU cycles
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_secs(secs: u64) -> Self {
+        Self::from_units(secs, 1)
+    }
+
+    pub fn from_millis(millis: u64) -> Self {
+        Self::from_units(millis, 1_000)
+    }
+
+    pub fn from_micros(micros: u64) -> Self {
+        Self::from_units(micros, 1_000_000)
+    }
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self::from_units(nanos, 1_000_000_000)
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.as_units(1)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.as_units(1_000)
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.as_units(1_000_000)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.as_units(1_000_000_000)
+    }
+
+    pub const fn checked_add(&self, rhs: Duration) -> Option<Duration> {
+        match self.0.checked_add(rhs.0) {
+            Some(ticks) => Some(Duration(ticks)),
+            None => None,
+        }
+    }
+
+    pub const fn checked_sub(&self, rhs: Duration) -> Option<Duration> {
+        match self.0.checked_sub(rhs.0) {
+            Some(ticks) => Some(Duration(ticks)),
+            None => None,
+        }
+    }
+
+    pub const fn saturating_sub(&self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_sub(rhs.0))
+    }
+
+    /// `value` units of `1 / per_sec` seconds, rounded up to whole cycles
+    fn from_units(value: u64, per_sec: u64) -> Self {
+        let ticks =
+            (value as u128 * cpu_frequency() as u128 + per_sec as u128 - 1) / per_sec as u128;
+        Duration(ticks.min(u64::MAX as u128) as u64)
+    }
+
+    fn as_units(&self, per_sec: u64) -> u64 {
+        let units = self.0 as u128 * per_sec as u128 / cpu_frequency() as u128;
+        units.min(u64::MAX as u128) as u64
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        Duration(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Duration {
+    fn sub_assign(&mut self, rhs: Duration) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl From<core::time::Duration> for Duration {
+    fn from(duration: core::time::Duration) -> Self {
+        Duration::from_nanos(duration.as_nanos().min(u64::MAX as u128) as u64)
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(duration: Duration) -> Self {
+        core::time::Duration::from_nanos(duration.as_nanos())
+    }
+}
+
+/// Busy waiting delay based on [`Instant`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delay;
+
+impl Delay {
+    /// Wait until `duration` has passed
+    pub fn delay(&mut self, duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {}
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.delay(Duration::from_nanos(ns as u64));
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> From<fugit::Duration<u64, NOM, DENOM>> for Duration {
+    fn from(duration: fugit::Duration<u64, NOM, DENOM>) -> Self {
+        Duration::from_nanos(duration.to_nanos())
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> From<Duration> for fugit::Duration<u64, NOM, DENOM> {
+    fn from(duration: Duration) -> Self {
+        fugit::Duration::<u64, NOM, DENOM>::from_ticks(fugit_ticks(duration.0, NOM, DENOM))
+    }
+}
+
+/// The cycle counter as monotonic `fugit` instant, for schedulers built on `fugit` time types
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> From<Instant> for fugit::Instant<u64, NOM, DENOM> {
+    fn from(instant: Instant) -> Self {
+        fugit::Instant::<u64, NOM, DENOM>::from_ticks(fugit_ticks(instant.0, NOM, DENOM))
+    }
+}
+
+/// Convert CPU cycles to ticks of `NOM / DENOM` seconds, rounded down
+#[cfg(feature = "fugit")]
+fn fugit_ticks(cycles: u64, nom: u32, denom: u32) -> u64 {
+    let ticks = cycles as u128 * denom as u128 / (nom as u128 * cpu_frequency() as u128);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Extend CCOUNT to 64 bits, must be called with interrupts disabled
+unsafe fn extend(ccount: u32) -> u64 {
+    let counter = &mut COUNTERS[crate::core_id()];
+    if ccount < counter.last {
+        counter.high = counter.high.wrapping_add(1);
+    }
+    counter.last = ccount;
+    ((counter.high as u64) << 32) | ccount as u64
+}
+
+#[inline(always)]
+unsafe fn ccount() -> u32 {
+    let ccount: u32;
+    asm!("rsr.ccount {0}", out(reg) ccount, options(nostack));
+    ccount
+}
@@ -9,6 +9,8 @@ PROVIDE(__pre_init = DefaultPreInit);
 PROVIDE(__zero_bss = default_mem_hook);
 PROVIDE(__init_data = default_mem_hook);
 PROVIDE(__post_init = default_post_init);
+PROVIDE(__idle_hook = default_idle_hook);
+PROVIDE(__fatal_handler = default_fatal_handler);
 
 INCLUDE exception.x
 